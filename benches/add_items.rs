@@ -0,0 +1,146 @@
+//! Compares latency of `PostgresStorage::add_items`, which inserts whole order with single statement,
+//! with inserting same items one statement at a time in single transaction
+//!
+//! Requires external PostgreSQL instance, configured with same env vars as tests:
+//! `PG_HOST=localhost PG_PORT=5432 PG_USER=paidy PG_PASS=paidy cargo bench --bench add_items`
+//! Without those env vars benchmark does nothing
+
+use std::env;
+
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use deadpool_postgres::{Config, Pool};
+use paidy_restaurant_api::storage::model::{NewItem, Storage};
+use paidy_restaurant_api::storage::pg::{migrations::migrate_up, PostgresStorage};
+use rand::RngCore;
+use tokio::runtime::Runtime;
+use tokio_postgres::{IsolationLevel, NoTls};
+
+const ITEM_COUNTS: &[usize] = &[1, 5, 20];
+
+fn base_config() -> Option<Config> {
+    let mut cfg = Config::new();
+    cfg.host = Some(env::var("PG_HOST").ok()?);
+    cfg.port = Some(env::var("PG_PORT").ok()?.parse().unwrap());
+    cfg.user = Some(env::var("PG_USER").ok()?);
+    cfg.password = Some(env::var("PG_PASS").ok()?);
+    Some(cfg)
+}
+
+/// Returns name of created database along with pool, to drop it once benchmark is done
+async fn create_bench_database(mut cfg: Config) -> (Pool, String) {
+    let dbname = format!("add_items_bench_{:08x}", rand::thread_rng().next_u32());
+
+    cfg.dbname = Some("postgres".into());
+    let db = cfg.create_pool(None, NoTls).unwrap().get().await.unwrap();
+    db.simple_query(
+        // CREATE DATABASE does not support parameters
+        // language=PostgreSQL
+        format!("CREATE DATABASE {dbname};").as_str(),
+    )
+    .await
+    .unwrap();
+
+    cfg.dbname = Some(dbname.clone());
+    let pool = cfg.create_pool(None, NoTls).unwrap();
+    migrate_up(&pool).await.unwrap();
+    (pool, dbname)
+}
+
+/// FORCE terminates connections that are still open, e.g. idle ones of pool
+async fn drop_bench_database(mut cfg: Config, dbname: &str) {
+    cfg.dbname = Some("postgres".into());
+    let db = cfg.create_pool(None, NoTls).unwrap().get().await.unwrap();
+    db.simple_query(
+        // language=PostgreSQL
+        format!("DROP DATABASE {dbname} WITH (FORCE);").as_str(),
+    )
+    .await
+    .unwrap();
+}
+
+fn new_items(count: usize) -> Vec<NewItem> {
+    (0..count)
+        .map(|idx| NewItem {
+            menu_item_id: 1.into(),
+            name: format!("bench item {idx}"),
+            comment: "bench item comment".into(),
+            created_at: Utc::now(),
+            forecast_ready_at: Utc::now(),
+        })
+        .collect()
+}
+
+/// Same thing `add_items` did before switching to single statement
+async fn add_items_one_by_one(pool: &Pool, table_id: i32, items: Vec<NewItem>) {
+    let mut db = pool.get().await.unwrap();
+    let txn = db
+        .build_transaction()
+        .isolation_level(IsolationLevel::Serializable)
+        .start()
+        .await
+        .unwrap();
+    for item in items {
+        txn.execute(
+            // language=PostgreSQL
+            "
+                INSERT INTO
+                    items
+                    (table_id, name, comment, created_at, forecast_ready_at)
+                VALUES
+                    ($1, $2, $3, $4, $5)
+            ",
+            &[
+                &table_id,
+                &item.name,
+                &item.comment,
+                &item.created_at,
+                &item.forecast_ready_at,
+            ],
+        )
+        .await
+        .unwrap();
+    }
+    txn.commit().await.unwrap();
+}
+
+fn bench_add_items(c: &mut Criterion) {
+    let Some(cfg) = base_config() else {
+        eprintln!(
+            "PG_HOST, PG_PORT, PG_USER and PG_PASS are not set, skipping add_items benchmark"
+        );
+        return;
+    };
+
+    let rt = Runtime::new().unwrap();
+    let (pool, dbname) = rt.block_on(create_bench_database(cfg.clone()));
+    let storage = rt.block_on(PostgresStorage::new(pool.clone())).unwrap();
+
+    let mut group = c.benchmark_group("add_items");
+    for &count in ITEM_COUNTS {
+        group.bench_with_input(
+            BenchmarkId::new("single_statement", count),
+            &count,
+            |b, &count| {
+                b.to_async(&rt)
+                    .iter(|| storage.add_items(1.into(), None, new_items(count).into_iter()))
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("statement_per_item", count),
+            &count,
+            |b, &count| {
+                b.to_async(&rt)
+                    .iter(|| add_items_one_by_one(&pool, 1, new_items(count)))
+            },
+        );
+    }
+    group.finish();
+
+    drop(storage);
+    drop(pool);
+    rt.block_on(drop_bench_database(cfg, &dbname));
+}
+
+criterion_group!(benches, bench_add_items);
+criterion_main!(benches);
@@ -0,0 +1,261 @@
+//! Derive macro for `RowsParser` from `storage::pg::rows` of paidy-restaurant-api
+//!
+//! `#[derive(RowsParser)]` on struct generates separate parser type, `<Struct>Parser` by default,
+//! that builds column map once in `prepare`, and then uses it in `parse` call to avoid looking up
+//! column idx by name for every row.
+//!
+//! Attributes:
+//! * `#[rows_parser(parser = SomeName)]` on struct to change name of generated parser
+//! * `#[rows_parser(into = SomeType)]` on struct to output `SomeType` via `From`, so row struct could
+//!   stay private to storage, while parser outputs model type
+//! * `#[rows_parser(rename = "column")]` on field to read it from column with different name
+//! * `#[rows_parser(native = i32)]` on field to read it as native type, and then convert with `Into`
+//!
+//! Generated code refers to `crate::storage::pg`, so it can only be used inside that crate.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{Data, DeriveInput, Fields, Ident, LitStr, Type};
+
+#[proc_macro_derive(RowsParser, attributes(rows_parser))]
+pub fn derive_rows_parser(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct FieldSpec {
+    field: Ident,
+    column: LitStr,
+    native_ty: Option<Type>,
+}
+
+struct StructSpec {
+    parser: Option<Ident>,
+    into_ty: Option<Type>,
+}
+
+fn parse_struct_attrs(input: &DeriveInput) -> syn::Result<StructSpec> {
+    let mut parser = None;
+    let mut into_ty = None;
+    for attr in input.attrs.iter() {
+        if !attr.path().is_ident("rows_parser") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("parser") {
+                parser = Some(meta.value()?.parse::<Ident>()?);
+                Ok(())
+            } else if meta.path.is_ident("into") {
+                into_ty = Some(meta.value()?.parse::<Type>()?);
+                Ok(())
+            } else {
+                Err(meta
+                    .error("unknown rows_parser attribute on struct, expected `parser` or `into`"))
+            }
+        })?;
+    }
+    Ok(StructSpec { parser, into_ty })
+}
+
+fn parse_field(field: &syn::Field) -> syn::Result<FieldSpec> {
+    let ident = field
+        .ident
+        .clone()
+        .ok_or_else(|| syn::Error::new(field.span(), "RowsParser requires named fields"))?;
+
+    let mut column = None;
+    let mut native_ty = None;
+    for attr in field.attrs.iter() {
+        if !attr.path().is_ident("rows_parser") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                column = Some(meta.value()?.parse::<LitStr>()?);
+                Ok(())
+            } else if meta.path.is_ident("native") {
+                native_ty = Some(meta.value()?.parse::<Type>()?);
+                Ok(())
+            } else {
+                Err(meta
+                    .error("unknown rows_parser attribute on field, expected `rename` or `native`"))
+            }
+        })?;
+    }
+
+    Ok(FieldSpec {
+        column: column.unwrap_or_else(|| LitStr::new(&ident.to_string(), ident.span())),
+        field: ident,
+        native_ty,
+    })
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            input.generics.span(),
+            "RowsParser can not be derived for generic struct",
+        ));
+    }
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields
+                .named
+                .iter()
+                .map(parse_field)
+                .collect::<syn::Result<Vec<_>>>()?,
+            _ => {
+                return Err(syn::Error::new(
+                    input.ident.span(),
+                    "RowsParser requires struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new(
+                input.ident.span(),
+                "RowsParser can only be derived for struct",
+            ))
+        }
+    };
+
+    for (idx, field) in fields.iter().enumerate() {
+        if let Some(other) = fields[..idx]
+            .iter()
+            .find(|other| other.column.value() == field.column.value())
+        {
+            return Err(syn::Error::new(
+                field.column.span(),
+                format!(
+                    "column `{}` is already used for field `{}`",
+                    field.column.value(),
+                    other.field
+                ),
+            ));
+        }
+    }
+
+    let vis = &input.vis;
+    let out_ty = &input.ident;
+    let struct_spec = parse_struct_attrs(&input)?;
+    let parser_ty = struct_spec
+        .parser
+        .unwrap_or_else(|| format_ident!("{}Parser", input.ident));
+    let (output_ty, into) = match &struct_spec.into_ty {
+        None => (quote! { #out_ty }, quote! {}),
+        Some(into_ty) => (quote! { #into_ty }, quote! { .into() }),
+    };
+    let len = fields.len();
+    let columns = fields.iter().map(|f| &f.column);
+
+    let parse_fields = fields.iter().enumerate().map(|(idx, f)| {
+        let field = &f.field;
+        match &f.native_ty {
+            None => quote! {
+                #field: crate::storage::pg::rows::try_get_field(&row, self.columns_map[#idx])?
+            },
+            Some(native_ty) => quote! {
+                #field: crate::storage::pg::rows::try_get_field::<#native_ty>(&row, self.columns_map[#idx])?.into()
+            },
+        }
+    });
+
+    let verify_fields = fields.iter().enumerate().map(|(idx, f)| {
+        let field = &f.field;
+        let column = &f.column;
+        let accepts = match &f.native_ty {
+            None => quote! {
+                crate::storage::pg::rows::field_accepts(|o: &#out_ty| &o.#field, ty)
+            },
+            Some(native_ty) => quote! {
+                <#native_ty as ::tokio_postgres::types::FromSql>::accepts(ty)
+            },
+        };
+        quote! {
+            {
+                let ty = columns[parser.columns_map[#idx]].type_();
+                crate::storage::pg::rows::check_column_type(#column, ty, #accepts)?;
+            }
+        }
+    });
+
+    Ok(quote! {
+        #vis struct #parser_ty {
+            columns_map: [usize; #len],
+        }
+
+        impl crate::storage::pg::rows::RowsParser for #parser_ty {
+            type Output = #output_ty;
+
+            fn prepare(
+                columns: &[::tokio_postgres::Column],
+            ) -> ::std::result::Result<Self, crate::storage::pg::PostgresStorageError> {
+                const COLUMNS: [&str; #len] = [#(#columns,)*];
+                let columns_map = crate::storage::pg::rows::build_column_map(&COLUMNS, columns)?;
+                Ok(Self { columns_map })
+            }
+
+            fn parse(
+                &self,
+                row: ::tokio_postgres::Row,
+            ) -> ::std::result::Result<Self::Output, crate::storage::pg::PostgresStorageError> {
+                Ok(#out_ty {
+                    #(#parse_fields,)*
+                }#into)
+            }
+
+            fn verify(
+                columns: &[::tokio_postgres::Column],
+            ) -> ::std::result::Result<(), crate::storage::pg::PostgresStorageError> {
+                let parser = Self::prepare(columns)?;
+                #(#verify_fields)*
+                Ok(())
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_err(input: TokenStream2) -> String {
+        expand(syn::parse2(input).unwrap()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_attribute_typos() {
+        assert!(expand_err(quote! {
+            struct Item {
+                #[rows_parser(natve = i32)]
+                item_id: ItemId,
+            }
+        })
+        .contains("unknown rows_parser attribute on field"));
+
+        assert!(expand_err(quote! {
+            #[rows_parser(parsr = ItemParser)]
+            struct Item {
+                item_id: i32,
+            }
+        })
+        .contains("unknown rows_parser attribute on struct"));
+    }
+
+    #[test]
+    fn test_duplicate_column() {
+        assert!(expand_err(quote! {
+            struct Item {
+                item_id: i32,
+                #[rows_parser(rename = "item_id")]
+                other_id: i32,
+            }
+        })
+        .contains("column `item_id` is already used for field `item_id`"));
+    }
+}
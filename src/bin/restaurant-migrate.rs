@@ -0,0 +1,75 @@
+use anyhow::anyhow;
+use clap::{Parser, Subcommand};
+use tracing::info;
+
+use paidy_restaurant_api::cli::{init_tracing, PostgresArgs};
+use paidy_restaurant_api::storage::pg::migrations::{self, MigrationState};
+use paidy_restaurant_api::storage::pg::PostgresStorage;
+
+/// Manage PostgreSQL schema. Should be run before rolling out new app version
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(flatten)]
+    postgres: PostgresArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Apply all pending migrations
+    Up,
+    /// Print state of every migration
+    Status,
+    /// Fail unless database schema is exactly the one this version expects
+    Verify,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    init_tracing();
+
+    let args = Args::parse();
+    let pool = args.postgres.create_pool().await?;
+
+    match args.command {
+        Command::Up => {
+            let applied = migrations::migrate_up(&pool).await?;
+            info!(?applied, "Database is up to date");
+        }
+        Command::Status => {
+            for status in migrations::status(&pool).await? {
+                let state = match status.state {
+                    MigrationState::Pending => "pending".to_string(),
+                    MigrationState::Applied(at) => format!("applied at {at}"),
+                    MigrationState::Unknown(at) => format!("UNKNOWN, applied at {at}"),
+                };
+                println!("{:>4} {:<32} {state}", status.version, status.name);
+            }
+        }
+        Command::Verify => {
+            let problems = migrations::status(&pool)
+                .await?
+                .into_iter()
+                .filter(|s| !matches!(s.state, MigrationState::Applied(_)))
+                .map(|s| format!("{} {} is {:?}", s.version, s.name, s.state))
+                .collect::<Vec<_>>();
+            if !problems.is_empty() {
+                return Err(anyhow!(
+                    "Schema does not match this version: {}",
+                    problems.join(", ")
+                ));
+            }
+            // Checks schema, and prepares every query used by storage
+            PostgresStorage::new(pool).await?;
+            info!(
+                version = migrations::latest_version(),
+                "Schema matches this version"
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,71 @@
+//! Command line options and setup shared between executables
+
+use deadpool_postgres::{Config, Pool, PoolConfig};
+
+#[derive(clap::Args, Debug)]
+pub struct PostgresArgs {
+    /// Postgres host
+    #[arg(long)]
+    pub postgres_host: String,
+
+    /// Postgres port
+    #[arg(long, default_value_t = 5432)]
+    pub postgres_port: u16,
+
+    /// Postgres username
+    #[arg(long, env)]
+    pub postgres_username: String,
+
+    /// Postgres password
+    #[arg(long, env)]
+    pub postgres_password: String,
+
+    /// Postgres database
+    #[arg(long)]
+    pub postgres_database: String,
+
+    /// Postgres connections pool size
+    #[arg(long, default_value_t = 10)]
+    pub postgres_pool: usize,
+}
+
+impl PostgresArgs {
+    fn config(&self) -> Config {
+        let mut cfg = Config::new();
+        cfg.host = Some(self.postgres_host.clone());
+        cfg.port = Some(self.postgres_port);
+        cfg.user = Some(self.postgres_username.clone());
+        cfg.password = Some(self.postgres_password.clone());
+        cfg.dbname = Some(self.postgres_database.clone());
+        cfg.pool = Some(PoolConfig {
+            max_size: self.postgres_pool,
+            ..Default::default()
+        });
+        cfg
+    }
+
+    /// For connections outside of pool, e.g. to listen for notifications
+    pub fn pg_config(&self) -> anyhow::Result<tokio_postgres::Config> {
+        Ok(self.config().get_pg_config()?)
+    }
+
+    pub async fn create_pool(&self) -> anyhow::Result<Pool> {
+        use deadpool_postgres::tokio_postgres::NoTls;
+
+        let pool = self.config().create_pool(None, NoTls)?;
+        {
+            // Just to check connectivity
+            let db = pool.get().await?;
+            drop(db);
+        }
+        Ok(pool)
+    }
+}
+
+pub fn init_tracing() {
+    use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+    tracing_subscriber::registry()
+        .with(fmt::layer().pretty())
+        .with(EnvFilter::from_default_env())
+        .init();
+}
@@ -0,0 +1,254 @@
+//! Forecasting of `forecast_ready_at` for new items
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::storage::model::{ItemInfo, ItemStatus, KitchenStats, MenuItemId};
+
+/// Estimates when an item will be ready, based on latest `KitchenStats`
+/// Stats are fed by service, see `DefaultRestaurantService::refresh_forecaster`
+pub trait Forecaster {
+    /// How far back kitchen stats should go
+    fn window(&self) -> Duration;
+
+    /// Replace stats that forecasts are based on
+    fn update(&self, stats: KitchenStats);
+
+    /// Time from now until item of this menu item will be ready, if ordered now
+    fn forecast(&self, menu_item_id: &MenuItemId) -> Duration;
+
+    /// Forecasts for items that are not ready yet, in same order as items
+    /// Items should be passed in order kitchen takes them, so later ones wait for earlier ones
+    fn forecast_outstanding(&self, items: &[ItemInfo], now: DateTime<Utc>) -> Vec<DateTime<Utc>>;
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoricalForecasterConfig {
+    pub window: Duration,
+    /// Used for menu items without any items that got ready within window,
+    /// and for all of them until there's any history at all
+    pub default_prep_time: Duration,
+    /// How many items kitchen cooks at the same time
+    pub stations: u32,
+}
+
+impl Default for HistoricalForecasterConfig {
+    fn default() -> Self {
+        HistoricalForecasterConfig {
+            window: Duration::hours(8),
+            default_prep_time: Duration::minutes(10),
+            stations: 4,
+        }
+    }
+}
+
+/// Forecast is average cooking time of same menu item, plus time to cook everything
+/// that is queued before it
+pub struct HistoricalForecaster {
+    config: HistoricalForecasterConfig,
+    state: RwLock<HistoricalForecasterState>,
+}
+
+#[derive(Default)]
+struct HistoricalForecasterState {
+    prep_times: HashMap<MenuItemId, Duration>,
+    /// Average over all menu items, weighted by count of completed items
+    average_prep_time: Option<Duration>,
+    queued: i64,
+}
+
+impl HistoricalForecasterState {
+    fn average_prep_time(&self, config: &HistoricalForecasterConfig) -> Duration {
+        self.average_prep_time.unwrap_or(config.default_prep_time)
+    }
+
+    fn prep_time(
+        &self,
+        config: &HistoricalForecasterConfig,
+        menu_item_id: &MenuItemId,
+    ) -> Duration {
+        self.prep_times
+            .get(menu_item_id)
+            .copied()
+            .unwrap_or_else(|| self.average_prep_time(config))
+    }
+
+    /// Queue is drained by all stations in parallel
+    fn wait(&self, config: &HistoricalForecasterConfig, ahead: i64) -> Duration {
+        self.average_prep_time(config) * ahead as i32 / config.stations.max(1) as i32
+    }
+}
+
+impl HistoricalForecaster {
+    pub fn new(config: HistoricalForecasterConfig) -> HistoricalForecaster {
+        HistoricalForecaster {
+            config,
+            state: Default::default(),
+        }
+    }
+}
+
+impl Default for HistoricalForecaster {
+    fn default() -> Self {
+        HistoricalForecaster::new(Default::default())
+    }
+}
+
+impl Forecaster for HistoricalForecaster {
+    fn window(&self) -> Duration {
+        self.config.window
+    }
+
+    fn update(&self, stats: KitchenStats) {
+        let completed = stats.prep_times.iter().map(|p| p.completed).sum::<i64>();
+        let total = stats.prep_times.iter().fold(Duration::zero(), |total, p| {
+            total + p.average * p.completed as i32
+        });
+        let state = HistoricalForecasterState {
+            average_prep_time: (completed > 0).then(|| total / completed as i32),
+            prep_times: stats
+                .prep_times
+                .into_iter()
+                .map(|p| (p.menu_item_id, p.average))
+                .collect(),
+            queued: stats.queued,
+        };
+        *self.state.write().unwrap() = state;
+    }
+
+    fn forecast(&self, menu_item_id: &MenuItemId) -> Duration {
+        let state = self.state.read().unwrap();
+        state.wait(&self.config, state.queued) + state.prep_time(&self.config, menu_item_id)
+    }
+
+    fn forecast_outstanding(&self, items: &[ItemInfo], now: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        let state = self.state.read().unwrap();
+        let prep_time = |item: &ItemInfo| match &item.menu_item_id {
+            Some(menu_item_id) => state.prep_time(&self.config, menu_item_id),
+            None => state.average_prep_time(&self.config),
+        };
+
+        // Items that are cooking already are ahead of every ordered one
+        let mut ahead = items
+            .iter()
+            .filter(|item| item.status == ItemStatus::Cooking)
+            .count() as i64;
+        items
+            .iter()
+            .map(|item| match (item.status, item.cooking_at) {
+                // Overdue item is expected to get ready any moment
+                (ItemStatus::Cooking, Some(cooking_at)) => (cooking_at + prep_time(item)).max(now),
+                _ => {
+                    let forecast = now + state.wait(&self.config, ahead) + prep_time(item);
+                    ahead += 1;
+                    forecast
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::model::PrepTime;
+
+    #[test]
+    fn test_historical_forecast() {
+        let forecaster = HistoricalForecaster::new(HistoricalForecasterConfig {
+            stations: 2,
+            ..Default::default()
+        });
+        let ramen: MenuItemId = 1.into();
+        let gyoza: MenuItemId = 2.into();
+        let udon: MenuItemId = 3.into();
+
+        // No history yet
+        assert_eq!(forecaster.forecast(&ramen), Duration::minutes(10));
+
+        forecaster.update(KitchenStats {
+            prep_times: vec![
+                PrepTime {
+                    menu_item_id: ramen.clone(),
+                    completed: 1,
+                    average: Duration::minutes(12),
+                },
+                PrepTime {
+                    menu_item_id: gyoza.clone(),
+                    completed: 3,
+                    average: Duration::minutes(4),
+                },
+            ],
+            queued: 0,
+        });
+        assert_eq!(forecaster.forecast(&ramen), Duration::minutes(12));
+        assert_eq!(forecaster.forecast(&gyoza), Duration::minutes(4));
+        // Weighted average of all items
+        assert_eq!(forecaster.forecast(&udon), Duration::minutes(6));
+
+        forecaster.update(KitchenStats {
+            prep_times: vec![PrepTime {
+                menu_item_id: gyoza.clone(),
+                completed: 1,
+                average: Duration::minutes(4),
+            }],
+            queued: 5,
+        });
+        // 5 items of 4 minutes on 2 stations
+        assert_eq!(forecaster.forecast(&gyoza), Duration::minutes(14));
+        assert_eq!(forecaster.forecast(&ramen), Duration::minutes(14));
+    }
+
+    #[test]
+    fn test_forecast_outstanding() {
+        let forecaster = HistoricalForecaster::new(HistoricalForecasterConfig {
+            stations: 2,
+            ..Default::default()
+        });
+        forecaster.update(KitchenStats {
+            prep_times: vec![PrepTime {
+                menu_item_id: 1.into(),
+                completed: 1,
+                average: Duration::minutes(4),
+            }],
+            queued: 0,
+        });
+
+        let now = DateTime::<Utc>::UNIX_EPOCH + Duration::hours(1);
+        let item = |status, cooking_at| ItemInfo {
+            table_id: 1.into(),
+            item_id: 1.into(),
+            menu_item_id: Some(1.into()),
+            name: "".into(),
+            comment: "".into(),
+            created_at: now,
+            forecast_ready_at: now,
+            status,
+            cooking_at,
+            ready_at: None,
+            served_at: None,
+        };
+        let items = [
+            item(ItemStatus::Ordered, None),
+            item(ItemStatus::Cooking, Some(now - Duration::minutes(1))),
+            item(ItemStatus::Cooking, Some(now - Duration::minutes(10))),
+            item(ItemStatus::Ordered, None),
+            item(ItemStatus::Ordered, None),
+        ];
+        assert_eq!(
+            forecaster.forecast_outstanding(&items, now),
+            vec![
+                // Waits for 2 cooking items on 2 stations
+                now + Duration::minutes(4 + 4),
+                now + Duration::minutes(3),
+                // Overdue
+                now,
+                now + Duration::minutes(6 + 4),
+                now + Duration::minutes(8 + 4),
+            ]
+        );
+    }
+}
@@ -0,0 +1,962 @@
+use async_trait::async_trait;
+use derive_more::From;
+use eventsource_stream::Eventsource;
+use futures_util::{future, StreamExt};
+use reqwest::{Client, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use tracing::{instrument, warn};
+
+use super::{IDEMPOTENCY_KEY_HEADER, TABLE_VERSION_HEADER};
+use crate::service::{ErrorKind, ItemEvents, NewItem, RestaurantService, ServiceError};
+use crate::storage::model::{
+    IdempotencyKey, ItemChanges, ItemId, ItemInfo, ItemInfoShort, ItemStatus, MenuItem, MenuItemId,
+    NewMenuItem, NewTable, RemovedItems, Table, TableId, TableVersion,
+};
+
+#[derive(Debug, Error, From)]
+pub enum HttpRestaurantClientError {
+    #[error("HTTP request failed: {0}")]
+    Transport(reqwest::Error),
+    #[error("server responded with {status}: {body}")]
+    #[from(ignore)]
+    Status { status: StatusCode, body: String },
+    #[error("failed to decode response: {0}")]
+    Decode(serde_json::Error),
+    #[error("response has no valid {TABLE_VERSION_HEADER} header")]
+    #[from(ignore)]
+    MissingVersion,
+}
+
+/// Kind is restored from status set by server, see `status_for_kind` in `http::server`
+impl ServiceError for HttpRestaurantClientError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            HttpRestaurantClientError::Status { status, .. } => match *status {
+                StatusCode::NOT_FOUND => ErrorKind::NotFound,
+                StatusCode::CONFLICT => ErrorKind::Conflict,
+                StatusCode::UNPROCESSABLE_ENTITY => ErrorKind::Invalid,
+                _ => ErrorKind::Internal,
+            },
+            HttpRestaurantClientError::Transport(_)
+            | HttpRestaurantClientError::Decode(_)
+            | HttpRestaurantClientError::MissingVersion => ErrorKind::Internal,
+        }
+    }
+}
+
+/// RestaurantService implementation on top of HTTP API served by `http::server`
+pub struct HttpRestaurantClient {
+    client: Client,
+    base_url: String,
+}
+
+impl HttpRestaurantClient {
+    /// `base_url` is prepended to every route, e.g. `http://localhost:8080`
+    pub fn new(base_url: Url) -> HttpRestaurantClient {
+        HttpRestaurantClient {
+            client: Client::new(),
+            base_url: base_url.as_str().trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn tables_url(&self) -> String {
+        format!("{}/tables", self.base_url)
+    }
+
+    fn table_url(&self, table_id: &TableId) -> String {
+        format!("{}/tables/{table_id}", self.base_url)
+    }
+
+    fn table_active_url(&self, table_id: &TableId) -> String {
+        format!("{}/active", self.table_url(table_id))
+    }
+
+    fn events_url(&self) -> String {
+        format!("{}/events", self.base_url)
+    }
+
+    fn table_events_url(&self, table_id: &TableId) -> String {
+        format!("{}/tables/{table_id}/events", self.base_url)
+    }
+
+    fn items_url(&self, table_id: &TableId) -> String {
+        format!("{}/tables/{table_id}/items", self.base_url)
+    }
+
+    fn wait_items_url(
+        &self,
+        table_id: &TableId,
+        after: TableVersion,
+        timeout: std::time::Duration,
+    ) -> String {
+        format!(
+            "{}?wait_for_change_after={after}&timeout={}ms",
+            self.items_url(table_id),
+            timeout.as_millis()
+        )
+    }
+
+    fn changes_url(&self, table_id: &TableId, since: TableVersion) -> String {
+        format!("{}/tables/{table_id}/changes?since={since}", self.base_url)
+    }
+
+    fn item_url(&self, table_id: &TableId, item_id: &ItemId) -> String {
+        format!("{}/tables/{table_id}/items/{item_id}", self.base_url)
+    }
+
+    fn item_status_url(&self, table_id: &TableId, item_id: &ItemId) -> String {
+        format!("{}/status", self.item_url(table_id, item_id))
+    }
+
+    fn menu_url(&self) -> String {
+        format!("{}/menu", self.base_url)
+    }
+
+    fn menu_item_available_url(&self, menu_item_id: &MenuItemId) -> String {
+        format!("{}/menu/{menu_item_id}/available", self.base_url)
+    }
+
+    fn table_version_header(
+        response: &Response,
+    ) -> Result<TableVersion, HttpRestaurantClientError> {
+        response
+            .headers()
+            .get(TABLE_VERSION_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<i64>().ok())
+            .map(TableVersion::from)
+            .ok_or(HttpRestaurantClientError::MissingVersion)
+    }
+
+    async fn check_status(response: Response) -> Result<Response, HttpRestaurantClientError> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        // Body is only for diagnostics, so failing to read it should not hide the status
+        let body = response.text().await.unwrap_or_default();
+        Err(HttpRestaurantClientError::Status { status, body })
+    }
+
+    async fn decode<T: DeserializeOwned>(
+        response: Response,
+    ) -> Result<T, HttpRestaurantClientError> {
+        let body = response.bytes().await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+#[async_trait]
+impl RestaurantService for HttpRestaurantClient {
+    type Error = HttpRestaurantClientError;
+
+    #[instrument(skip(self, items))]
+    async fn add_items(
+        &self,
+        table_id: TableId,
+        idempotency_key: Option<IdempotencyKey>,
+        items: impl Iterator<Item = NewItem> + Send,
+    ) -> Result<Vec<ItemInfo>, Self::Error> {
+        let items = items.collect::<Vec<_>>();
+        let mut request = self.client.post(self.items_url(&table_id)).json(&items);
+        if let Some(key) = idempotency_key {
+            request = request.header(IDEMPOTENCY_KEY_HEADER, key.as_str());
+        }
+        let response = request.send().await?;
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await
+    }
+
+    #[instrument(skip(self, item_ids))]
+    async fn remove_items(
+        &self,
+        table_id: TableId,
+        item_ids: impl Iterator<Item = ItemId> + Send,
+    ) -> Result<RemovedItems, Self::Error> {
+        let item_ids = item_ids.collect::<Vec<_>>();
+        let response = self
+            .client
+            .delete(self.items_url(&table_id))
+            .json(&item_ids)
+            .send()
+            .await?;
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await
+    }
+
+    #[instrument(skip(self))]
+    async fn list_items(&self, table_id: TableId) -> Result<Vec<ItemInfoShort>, Self::Error> {
+        let response = self.client.get(self.items_url(&table_id)).send().await?;
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await
+    }
+
+    #[instrument(skip(self))]
+    async fn changes_since(
+        &self,
+        table_id: TableId,
+        since: TableVersion,
+    ) -> Result<ItemChanges, Self::Error> {
+        let response = self
+            .client
+            .get(self.changes_url(&table_id, since))
+            .send()
+            .await?;
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await
+    }
+
+    /// `HEAD` of items responds with their version only
+    #[instrument(skip(self))]
+    async fn table_version(&self, table_id: TableId) -> Result<TableVersion, Self::Error> {
+        let response = self.client.head(self.items_url(&table_id)).send().await?;
+        let response = Self::check_status(response).await?;
+        Self::table_version_header(&response)
+    }
+
+    #[instrument(skip(self))]
+    async fn get_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+    ) -> Result<Option<ItemInfo>, Self::Error> {
+        let response = self
+            .client
+            .get(self.item_url(&table_id, &item_id))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await.map(Some)
+    }
+
+    /// Missing item is reported with version as well
+    #[instrument(skip(self))]
+    async fn get_versioned_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+    ) -> Result<(Option<ItemInfo>, TableVersion), Self::Error> {
+        let response = self
+            .client
+            .get(self.item_url(&table_id, &item_id))
+            .send()
+            .await?;
+        let version = Self::table_version_header(&response);
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok((None, version?));
+        }
+        let response = Self::check_status(response).await?;
+        Ok((Some(Self::decode(response).await?), version?))
+    }
+
+    #[instrument(skip(self))]
+    async fn advance_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+        to: ItemStatus,
+    ) -> Result<ItemInfo, Self::Error> {
+        let response = self
+            .client
+            .put(self.item_status_url(&table_id, &item_id))
+            .json(&to)
+            .send()
+            .await?;
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await
+    }
+
+    #[instrument(skip(self, items))]
+    async fn add_menu_items(
+        &self,
+        items: impl Iterator<Item = NewMenuItem> + Send,
+    ) -> Result<Vec<MenuItem>, Self::Error> {
+        let items = items.collect::<Vec<_>>();
+        let response = self
+            .client
+            .post(self.menu_url())
+            .json(&items)
+            .send()
+            .await?;
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await
+    }
+
+    #[instrument(skip(self))]
+    async fn list_menu(&self) -> Result<Vec<MenuItem>, Self::Error> {
+        let response = self.client.get(self.menu_url()).send().await?;
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await
+    }
+
+    #[instrument(skip(self))]
+    async fn set_menu_item_available(
+        &self,
+        menu_item_id: MenuItemId,
+        available: bool,
+    ) -> Result<MenuItem, Self::Error> {
+        let response = self
+            .client
+            .put(self.menu_item_available_url(&menu_item_id))
+            .json(&available)
+            .send()
+            .await?;
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await
+    }
+
+    #[instrument(skip(self, tables))]
+    async fn add_tables(
+        &self,
+        tables: impl Iterator<Item = NewTable> + Send,
+    ) -> Result<Vec<Table>, Self::Error> {
+        let tables = tables.collect::<Vec<_>>();
+        let response = self
+            .client
+            .post(self.tables_url())
+            .json(&tables)
+            .send()
+            .await?;
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await
+    }
+
+    #[instrument(skip(self))]
+    async fn list_tables(&self) -> Result<Vec<Table>, Self::Error> {
+        let response = self.client.get(self.tables_url()).send().await?;
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_table(&self, table_id: TableId) -> Result<Option<Table>, Self::Error> {
+        let response = self.client.get(self.table_url(&table_id)).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await.map(Some)
+    }
+
+    #[instrument(skip(self))]
+    async fn set_table_active(
+        &self,
+        table_id: TableId,
+        active: bool,
+    ) -> Result<Table, Self::Error> {
+        let response = self
+            .client
+            .put(self.table_active_url(&table_id))
+            .json(&active)
+            .send()
+            .await?;
+        let response = Self::check_status(response).await?;
+        Self::decode(response).await
+    }
+
+    #[instrument(skip(self))]
+    async fn wait_for_changes(
+        &self,
+        table_id: TableId,
+        after: TableVersion,
+        timeout: std::time::Duration,
+    ) -> Result<Option<ItemChanges>, Self::Error> {
+        let response = self
+            .client
+            .get(self.wait_items_url(&table_id, after, timeout))
+            .send()
+            .await?;
+        if matches!(
+            response.status(),
+            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+        ) {
+            return Ok(None);
+        }
+        let response = Self::check_status(response).await?;
+        let version = Self::table_version_header(&response)?;
+        Ok(Some(ItemChanges {
+            version,
+            reset: true,
+            changed: Self::decode(response).await?,
+            removed: vec![],
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn subscribe(&self, table_id: Option<TableId>) -> Result<ItemEvents, Self::Error> {
+        let url = match &table_id {
+            Some(table_id) => self.table_events_url(table_id),
+            None => self.events_url(),
+        };
+        let response = self.client.get(url).send().await?;
+        let response = Self::check_status(response).await?;
+
+        let events = response
+            .bytes_stream()
+            .eventsource()
+            // Broken connection ends stream, same as lagging behind on server does
+            .take_while(|event| future::ready(event.is_ok()))
+            .filter_map(|event| {
+                let event = event.ok().and_then(|event| {
+                    serde_json::from_str(&event.data)
+                        .inspect_err(|error| warn!(%error, "Skipping malformed event"))
+                        .ok()
+                });
+                future::ready(event)
+            });
+        Ok(Box::pin(events))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::forecast::HistoricalForecaster;
+    use crate::http::server::router;
+    use crate::service::DefaultRestaurantService;
+    use crate::storage::memory::SimpleMemoryStorage;
+    use crate::storage::model::ItemEvent;
+
+    async fn start_server() -> Url {
+        let service = DefaultRestaurantService::new(
+            SimpleMemoryStorage::default(),
+            HistoricalForecaster::default(),
+        );
+        let router = router(Arc::new(service), CancellationToken::new())
+            .await
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{addr}").parse().unwrap()
+    }
+
+    async fn add_test_table(client: &HttpRestaurantClient) -> TableId {
+        let tables = client
+            .add_tables(
+                [NewTable {
+                    name: "T1".into(),
+                    seats: 4,
+                    section: "test section".into(),
+                }]
+                .into_iter(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(client.list_tables().await.unwrap(), tables);
+        tables[0].table_id.clone()
+    }
+
+    async fn add_test_menu_item(client: &HttpRestaurantClient) -> MenuItemId {
+        let menu = client
+            .add_menu_items(
+                [NewMenuItem {
+                    name: "test new item".into(),
+                    category: "test category".into(),
+                    available: true,
+                }]
+                .into_iter(),
+            )
+            .await
+            .unwrap();
+        menu[0].menu_item_id.clone()
+    }
+
+    #[tokio::test]
+    async fn test_client_roundtrip() {
+        let client = HttpRestaurantClient::new(start_server().await);
+        let table_id = add_test_table(&client).await;
+
+        assert!(client
+            .list_items(table_id.clone())
+            .await
+            .unwrap()
+            .is_empty());
+
+        let menu = client
+            .add_menu_items(
+                [NewMenuItem {
+                    name: "test new item".into(),
+                    category: "test category".into(),
+                    available: true,
+                }]
+                .into_iter(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(client.list_menu().await.unwrap(), menu);
+
+        let new_item = NewItem {
+            menu_item_id: menu[0].menu_item_id.clone(),
+            comment: "test new item comment".into(),
+        };
+        let key = IdempotencyKey::from("test key".to_string());
+        let added = client
+            .add_items(table_id.clone(), Some(key.clone()), [new_item].into_iter())
+            .await
+            .unwrap();
+        assert_eq!(added.len(), 1);
+
+        // Retry with same key is passed to server and does not add anything
+        let retried = client
+            .add_items(table_id.clone(), Some(key), [].into_iter())
+            .await
+            .unwrap();
+        assert_eq!(retried, added);
+
+        let items = client.list_items(table_id.clone()).await.unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].name, "test new item");
+        assert_eq!(items[0].item_id, added[0].item_id);
+
+        let item_id = items[0].item_id.clone();
+        let item = client
+            .get_item(table_id.clone(), item_id.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.comment, "test new item comment");
+
+        let item = client
+            .advance_item(table_id.clone(), item_id.clone(), ItemStatus::Cooking)
+            .await
+            .unwrap();
+        assert_eq!(item.status, ItemStatus::Cooking);
+        let err = client
+            .advance_item(table_id.clone(), item_id.clone(), ItemStatus::Served)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Conflict);
+
+        let removed = client
+            .remove_items(table_id.clone(), [item_id.clone()].into_iter())
+            .await
+            .unwrap();
+        assert_eq!(removed.removed, vec![item_id.clone()]);
+        assert!(client
+            .list_items(table_id.clone())
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(client.get_item(table_id, item_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_client_menu_validation() {
+        let client = HttpRestaurantClient::new(start_server().await);
+        let table_id = add_test_table(&client).await;
+
+        let menu = client
+            .add_menu_items(
+                [NewMenuItem {
+                    name: "sold out item".into(),
+                    category: "test category".into(),
+                    available: true,
+                }]
+                .into_iter(),
+            )
+            .await
+            .unwrap();
+        let menu_item_id = menu[0].menu_item_id.clone();
+        let unavailable = client
+            .set_menu_item_available(menu_item_id.clone(), false)
+            .await
+            .unwrap();
+        assert!(!unavailable.available);
+
+        let new_item = |menu_item_id| NewItem {
+            menu_item_id,
+            comment: "".into(),
+        };
+        for menu_item_id in [menu_item_id, 1000.into()] {
+            let err = client
+                .add_items(table_id.clone(), None, [new_item(menu_item_id)].into_iter())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Invalid);
+        }
+        assert!(client.list_items(table_id).await.unwrap().is_empty());
+
+        let err = client
+            .set_menu_item_available(1000.into(), true)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_client_retry_after_sold_out() {
+        let client = HttpRestaurantClient::new(start_server().await);
+        let table_id = add_test_table(&client).await;
+        let menu_item_id = add_test_menu_item(&client).await;
+        let new_item = || NewItem {
+            menu_item_id: menu_item_id.clone(),
+            comment: "".into(),
+        };
+        let key = IdempotencyKey::from("test key".to_string());
+
+        let added = client
+            .add_items(
+                table_id.clone(),
+                Some(key.clone()),
+                [new_item()].into_iter(),
+            )
+            .await
+            .unwrap();
+        client
+            .set_menu_item_available(menu_item_id.clone(), false)
+            .await
+            .unwrap();
+
+        // Response of first request was lost, and tablet retries
+        let retried = client
+            .add_items(table_id.clone(), Some(key), [new_item()].into_iter())
+            .await
+            .unwrap();
+        assert_eq!(retried, added);
+        let other_key = IdempotencyKey::from("other key".to_string());
+        let err = client
+            .add_items(table_id, Some(other_key), [new_item()].into_iter())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Invalid);
+    }
+
+    #[tokio::test]
+    async fn test_client_table_validation() {
+        let client = HttpRestaurantClient::new(start_server().await);
+        let table_id = add_test_table(&client).await;
+
+        let table = client.get_table(table_id.clone()).await.unwrap().unwrap();
+        assert_eq!(table.table_id, table_id);
+        assert!(client.get_table(1000.into()).await.unwrap().is_none());
+
+        let err = client
+            .add_items(1000.into(), None, [].into_iter())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        let table = client
+            .set_table_active(table_id.clone(), false)
+            .await
+            .unwrap();
+        assert!(!table.active);
+        let err = client
+            .add_items(table_id.clone(), None, [].into_iter())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Conflict);
+
+        client
+            .set_table_active(table_id.clone(), true)
+            .await
+            .unwrap();
+        client
+            .add_items(table_id, None, [].into_iter())
+            .await
+            .unwrap();
+
+        let err = client
+            .set_table_active(1000.into(), false)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_client_events() {
+        let client = HttpRestaurantClient::new(start_server().await);
+        let table_id = add_test_table(&client).await;
+        let other_table_id = client
+            .add_tables(
+                [NewTable {
+                    name: "T2".into(),
+                    seats: 2,
+                    section: "test section".into(),
+                }]
+                .into_iter(),
+            )
+            .await
+            .unwrap()[0]
+            .table_id
+            .clone();
+        let menu_item_id = add_test_menu_item(&client).await;
+        let new_item = || NewItem {
+            menu_item_id: menu_item_id.clone(),
+            comment: "".into(),
+        };
+
+        let all_events = client.subscribe(None).await.unwrap();
+        let table_events = client.subscribe(Some(table_id.clone())).await.unwrap();
+
+        let other = client
+            .add_items(other_table_id, None, [new_item()].into_iter())
+            .await
+            .unwrap();
+        let added = client
+            .add_items(table_id.clone(), None, [new_item()].into_iter())
+            .await
+            .unwrap();
+        let item_id = added[0].item_id.clone();
+        let advanced = client
+            .advance_item(table_id.clone(), item_id.clone(), ItemStatus::Cooking)
+            .await
+            .unwrap();
+        client
+            .remove_items(table_id.clone(), [item_id.clone()].into_iter())
+            .await
+            .unwrap();
+
+        let table_expected = vec![
+            ItemEvent::Added(added[0].clone()),
+            ItemEvent::Advanced(advanced),
+            ItemEvent::Removed { table_id, item_id },
+        ];
+        let mut all_expected = vec![ItemEvent::Added(other[0].clone())];
+        all_expected.extend(table_expected.iter().cloned());
+
+        let collect = |events: ItemEvents, count| {
+            tokio::time::timeout(
+                Duration::from_secs(5),
+                events.take(count).collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(collect(table_events, 3).await.unwrap(), table_expected);
+        assert_eq!(collect(all_events, 4).await.unwrap(), all_expected);
+
+        let result = client.subscribe(Some(1000.into())).await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_client_changes() {
+        let client = HttpRestaurantClient::new(start_server().await);
+        let table_id = add_test_table(&client).await;
+        let menu_item_id = add_test_menu_item(&client).await;
+        let new_item = || NewItem {
+            menu_item_id: menu_item_id.clone(),
+            comment: "".into(),
+        };
+
+        let added = client
+            .add_items(table_id.clone(), None, [new_item(), new_item()].into_iter())
+            .await
+            .unwrap();
+        let full = client
+            .changes_since(table_id.clone(), TableVersion::default())
+            .await
+            .unwrap();
+        assert!(full.reset);
+        assert_eq!(
+            full.changed,
+            client.list_items(table_id.clone()).await.unwrap()
+        );
+
+        client
+            .remove_items(table_id.clone(), [added[0].item_id.clone()].into_iter())
+            .await
+            .unwrap();
+        let changes = client
+            .changes_since(table_id.clone(), full.version)
+            .await
+            .unwrap();
+        assert!(!changes.reset);
+        assert!(changes.version > full.version);
+        assert!(changes.changed.is_empty());
+        assert_eq!(changes.removed, vec![added[0].item_id.clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_client_wait_for_changes() {
+        let url = start_server().await;
+        let client = HttpRestaurantClient::new(url.clone());
+        let table_id = add_test_table(&client).await;
+        let menu_item_id = add_test_menu_item(&client).await;
+
+        // Nothing has happened on table yet
+        let initial = TableVersion::default();
+        let wait =
+            |after| client.wait_for_changes(table_id.clone(), after, Duration::from_millis(100));
+        assert_eq!(wait(initial).await.unwrap(), None);
+
+        let waiter = {
+            let client = HttpRestaurantClient::new(url);
+            let table_id = table_id.clone();
+            tokio::spawn(async move {
+                client
+                    .wait_for_changes(table_id, initial, Duration::from_secs(5))
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let new_item = NewItem {
+            menu_item_id,
+            comment: "".into(),
+        };
+        let added = client
+            .add_items(table_id.clone(), None, [new_item].into_iter())
+            .await
+            .unwrap();
+
+        let changes = waiter.await.unwrap().unwrap().unwrap();
+        assert!(changes.version > initial);
+        assert_eq!(changes.changed, vec![added[0].to_short()]);
+        // Already past that version
+        assert_eq!(wait(initial).await.unwrap(), Some(changes.clone()));
+        assert_eq!(wait(changes.version).await.unwrap(), None);
+
+        let result = client
+            .wait_for_changes(1000.into(), initial, Duration::from_millis(100))
+            .await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_long_poll_timeout() {
+        let client = HttpRestaurantClient::new(start_server().await);
+        let table_id = add_test_table(&client).await;
+        let version = client.table_version(table_id.clone()).await.unwrap();
+        let url = client.wait_items_url(&table_id, version, Duration::from_millis(100));
+        let etag = format!("W/\"{version}\"");
+
+        let get = |etag: Option<&str>| {
+            let mut request = client.client.get(&url);
+            if let Some(etag) = etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            request.send()
+        };
+
+        // Unconditional request has nothing to be not modified against
+        let response = get(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[reqwest::header::ETAG], etag.as_str());
+        assert_eq!(
+            HttpRestaurantClient::table_version_header(&response).unwrap(),
+            version
+        );
+        let response = get(Some("\"other\"")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = get(Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[reqwest::header::ETAG], etag.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_conditional_get() {
+        let url = start_server().await;
+        let client = HttpRestaurantClient::new(url.clone());
+        let table_id = add_test_table(&client).await;
+        let menu_item_id = add_test_menu_item(&client).await;
+        let new_item = || NewItem {
+            menu_item_id: menu_item_id.clone(),
+            comment: "".into(),
+        };
+        let added = client
+            .add_items(table_id.clone(), None, [new_item()].into_iter())
+            .await
+            .unwrap();
+        let items_url = client.items_url(&table_id);
+        let item_url = client.item_url(&table_id, &added[0].item_id);
+
+        let get = |url: &str, etag: Option<&str>| {
+            let mut request = client.client.get(url);
+            if let Some(etag) = etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            request.send()
+        };
+        let etag_of = |response: &Response| {
+            response.headers()[reqwest::header::ETAG]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let listed = get(&items_url, None).await.unwrap();
+        assert_eq!(listed.status(), StatusCode::OK);
+        let etag = etag_of(&listed);
+        let version = client.table_version(table_id.clone()).await.unwrap();
+        assert_eq!(
+            HttpRestaurantClient::table_version_header(&listed).unwrap(),
+            version
+        );
+        let item = get(&item_url, None).await.unwrap();
+        assert_eq!(etag_of(&item), etag);
+
+        for url in [&items_url, &item_url] {
+            let response = get(url, Some(&etag)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(etag_of(&response), etag);
+            let response = get(url, Some(&format!("\"other\", {etag}"))).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            let response = get(url, Some("*")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        }
+        // Nothing to match `*` against
+        let missing_url = client.item_url(&table_id, &1000.into());
+        let response = get(&missing_url, Some("*")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            client
+                .get_versioned_item(table_id.clone(), added[0].item_id.clone())
+                .await
+                .unwrap(),
+            (Some(added[0].clone()), version)
+        );
+        assert_eq!(
+            client
+                .get_versioned_item(table_id.clone(), 1000.into())
+                .await
+                .unwrap(),
+            (None, version)
+        );
+
+        client
+            .add_items(table_id.clone(), None, [new_item()].into_iter())
+            .await
+            .unwrap();
+        assert!(client.table_version(table_id.clone()).await.unwrap() > version);
+        for url in [&items_url, &item_url] {
+            let response = get(url, Some(&etag)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_ne!(etag_of(&response), etag);
+        }
+        let listed: Vec<ItemInfoShort> = get(&items_url, Some(&etag))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_client_status_error() {
+        let mut url = start_server().await;
+        url.set_path("/no-such-prefix");
+        let client = HttpRestaurantClient::new(url);
+
+        let result = client.list_items(1.into()).await;
+        assert!(matches!(
+            result,
+            Err(HttpRestaurantClientError::Status {
+                status: StatusCode::NOT_FOUND,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,38 @@
+//! HTTP/JSON API for RestaurantService
+//!
+//! Routes:
+//! * `GET /tables` - list all registered `Table`s
+//! * `POST /tables` - register tables, body is a list of `NewTable`, responds with created `Table`s
+//! * `GET /tables/{table_id}` - get single `Table`, 404 when not registered
+//! * `PUT /tables/{table_id}/active` - body is `true` or `false`, responds with updated `Table`
+//! * `POST /tables/{table_id}/items` - add items, body is a list of `NewItem`, responds with created `ItemInfo`s
+//!   404 for unregistered table, 409 for inactive one, 422 when any of items is not on the menu or not available
+//!   Optional `Idempotency-Key` header makes retries of same request safe
+//! * `DELETE /tables/{table_id}/items` - remove items, body is a list of item ids, responds with `RemovedItems`
+//! * `GET /tables/{table_id}/items` - list items as `ItemInfoShort`, with their version in `Table-Version` header
+//!   With `?wait_for_change_after={version}&timeout=30s` responds only once version of table differs,
+//!   or with 204 after timeout, 304 if request has matching `If-None-Match`. 404 for unregistered table then
+//!   `HEAD` responds with headers only, checking version without reading items
+//! * `GET /tables/{table_id}/changes?since={version}` - `ItemChanges` since version of table from
+//!   previous response, without `since` responds with all items
+//! * `GET /tables/{table_id}/items/{item_id}` - get single `ItemInfo`, 404 when not found
+//! * `PUT /tables/{table_id}/items/{item_id}/status` - move item to next `ItemStatus`, body is the status,
+//!   responds with updated `ItemInfo`, 409 when status is not next one
+//! * `GET /tables/{table_id}/events` - SSE stream of `ItemEvent`s of a table as JSON, 404 for unregistered table
+//! * `GET /events` - same for all tables
+//! * `GET /tables/{table_id}/ws` - WebSocket for tablets, with commands on items of a table and their
+//!   `ItemEvent`s on same connection, see `ws` module for protocol. 404 for unregistered table
+//! * `GET /menu` - list all `MenuItem`s
+//! * `POST /menu` - add menu items, body is a list of `NewMenuItem`, responds with created `MenuItem`s
+//! * `PUT /menu/{menu_item_id}/available` - body is `true` or `false`, responds with updated `MenuItem`
+//!
+//! Both `GET`s of items have `ETag` derived from version of table, and respond with 304 when it
+//! matches `If-None-Match`, list does that without reading items. `*` matches any existing item
+
+pub mod client;
+pub mod server;
+pub mod ws;
+
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+/// `TableVersion` that listed items are at
+const TABLE_VERSION_HEADER: &str = "Table-Version";
@@ -0,0 +1,389 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{Path, Query, State};
+use axum::http::header::{ETAG, IF_NONE_MATCH};
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, put};
+use axum::{Extension, Json, Router};
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use super::ws::{start_event_log, table_ws};
+use super::{IDEMPOTENCY_KEY_HEADER, TABLE_VERSION_HEADER};
+use crate::service::{ErrorKind, ItemEvents, NewItem, RestaurantService, ServiceError};
+use crate::storage::model::{
+    ItemChanges, ItemId, ItemInfo, ItemStatus, MenuItem, NewMenuItem, NewTable, RemovedItems,
+    Table, TableId, TableVersion,
+};
+
+/// Long polls are capped, so that clients could not hold connections for too long
+const MAX_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reports service error to client with status matching its kind, text of error goes to body
+pub(super) struct ErrorResponse<E>(E);
+
+impl<E> From<E> for ErrorResponse<E> {
+    fn from(e: E) -> Self {
+        ErrorResponse(e)
+    }
+}
+
+/// Inverse of this is in `HttpRestaurantClientError::kind`
+fn status_for_kind(kind: ErrorKind) -> StatusCode {
+    match kind {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::Conflict => StatusCode::CONFLICT,
+        ErrorKind::Invalid => StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Logs error that is about to be reported to client, returns its kind
+pub(super) fn log_error<E: ServiceError>(error: &E) -> ErrorKind {
+    let kind = error.kind();
+    if kind == ErrorKind::Internal {
+        error!(%error, "Service error");
+    } else {
+        warn!(%error, ?kind, "Request rejected");
+    }
+    kind
+}
+
+impl<E: ServiceError> IntoResponse for ErrorResponse<E> {
+    fn into_response(self) -> Response {
+        let kind = log_error(&self.0);
+        (status_for_kind(kind), self.0.to_string()).into_response()
+    }
+}
+
+/// Event streams and WebSocket connections are closed once `shutdown` is cancelled, otherwise
+/// they would hold graceful shutdown forever
+pub async fn router<S>(service: Arc<S>, shutdown: CancellationToken) -> Result<Router, S::Error>
+where
+    S: RestaurantService + Send + Sync + 'static,
+{
+    let event_log = start_event_log(service.clone(), shutdown.clone()).await?;
+    Ok(Router::new()
+        .route("/events", get(events::<S>))
+        .route("/tables", get(list_tables::<S>).post(add_tables::<S>))
+        .route("/tables/{table_id}", get(get_table::<S>))
+        .route("/tables/{table_id}/active", put(set_table_active::<S>))
+        .route(
+            "/tables/{table_id}/items",
+            get(list_items::<S>)
+                .post(add_items::<S>)
+                .delete(remove_items::<S>),
+        )
+        .route("/tables/{table_id}/changes", get(changes_since::<S>))
+        .route("/tables/{table_id}/events", get(table_events::<S>))
+        .route("/tables/{table_id}/ws", get(table_ws::<S>))
+        .route("/tables/{table_id}/items/{item_id}", get(get_item::<S>))
+        .route(
+            "/tables/{table_id}/items/{item_id}/status",
+            put(advance_item::<S>),
+        )
+        .route("/menu", get(list_menu::<S>).post(add_menu_items::<S>))
+        .route(
+            "/menu/{menu_item_id}/available",
+            put(set_menu_item_available::<S>),
+        )
+        .layer(Extension(event_log))
+        .layer(Extension(shutdown))
+        .with_state(service))
+}
+
+/// Serve HTTP API until token is cancelled, then shut down gracefully
+pub async fn serve<S>(
+    service: Arc<S>,
+    listen: SocketAddr,
+    token: CancellationToken,
+) -> anyhow::Result<()>
+where
+    S: RestaurantService + Send + Sync + 'static,
+    S::Error: Send + Sync + 'static,
+{
+    let router = router(service, token.clone()).await?;
+    let listener = TcpListener::bind(listen).await?;
+    info!(%listen, "Listening for HTTP connections");
+    axum::serve(listener, router)
+        .with_graceful_shutdown(token.cancelled_owned())
+        .await?;
+    info!("HTTP server stopped");
+    Ok(())
+}
+
+async fn add_items<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Path(table_id): Path<i32>,
+    headers: HeaderMap,
+    Json(items): Json<Vec<NewItem>>,
+) -> Result<Response, ErrorResponse<S::Error>> {
+    let idempotency_key = match headers.get(IDEMPOTENCY_KEY_HEADER).map(|v| v.to_str()) {
+        None => None,
+        Some(Ok(key)) => Some(key.to_string().into()),
+        Some(Err(_)) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                format!("{IDEMPOTENCY_KEY_HEADER} header should be visible ASCII"),
+            )
+                .into_response())
+        }
+    };
+    let items = service
+        .add_items(table_id.into(), idempotency_key, items.into_iter())
+        .await?;
+    Ok((StatusCode::CREATED, Json(items)).into_response())
+}
+
+async fn remove_items<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Path(table_id): Path<i32>,
+    Json(item_ids): Json<Vec<ItemId>>,
+) -> Result<Json<RemovedItems>, ErrorResponse<S::Error>> {
+    Ok(Json(
+        service
+            .remove_items(table_id.into(), item_ids.into_iter())
+            .await?,
+    ))
+}
+
+#[derive(Deserialize)]
+struct ListItemsParams {
+    wait_for_change_after: Option<TableVersion>,
+    /// Like `30s` or `500ms`
+    timeout: Option<String>,
+}
+
+fn parse_timeout(timeout: &str) -> Option<Duration> {
+    if let Some(ms) = timeout.strip_suffix("ms") {
+        return ms.parse().ok().map(Duration::from_millis);
+    }
+    let secs = timeout.strip_suffix('s').unwrap_or(timeout);
+    secs.parse().ok().map(Duration::from_secs)
+}
+
+/// Weak, as same version is shared by list of items and every item of table
+fn etag(version: TableVersion) -> String {
+    format!("W/\"{version}\"")
+}
+
+fn version_headers(version: TableVersion) -> [(&'static str, String); 2] {
+    [
+        (TABLE_VERSION_HEADER, version.to_string()),
+        (ETAG.as_str(), etag(version)),
+    ]
+}
+
+/// `If-None-Match` uses weak comparison, so `W/` prefix is ignored on both sides
+/// `*` matches any version, so it should be checked only when representation exists
+fn if_none_match(headers: &HeaderMap, version: TableVersion) -> bool {
+    let etag = etag(version);
+    let etag = etag.trim_start_matches("W/");
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+fn not_modified(version: TableVersion) -> Response {
+    (StatusCode::NOT_MODIFIED, version_headers(version)).into_response()
+}
+
+/// Items are listed via `changes_since` from scratch, to get them together with their version
+fn versioned_items(changes: ItemChanges) -> Response {
+    (version_headers(changes.version), Json(changes.changed)).into_response()
+}
+
+async fn list_items<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Path(table_id): Path<i32>,
+    Query(params): Query<ListItemsParams>,
+    method: Method,
+    headers: HeaderMap,
+    Extension(shutdown): Extension<CancellationToken>,
+) -> Result<Response, ErrorResponse<S::Error>> {
+    let table_id: TableId = table_id.into();
+    let Some(after) = params.wait_for_change_after else {
+        // Tablets refresh constantly, and most of the time nothing has changed
+        if method == Method::HEAD || headers.contains_key(IF_NONE_MATCH) {
+            let version = service.table_version(table_id.clone()).await?;
+            if if_none_match(&headers, version) {
+                return Ok(not_modified(version));
+            }
+            if method == Method::HEAD {
+                return Ok(version_headers(version).into_response());
+            }
+        }
+        let changes = service
+            .changes_since(table_id, TableVersion::default())
+            .await?;
+        return Ok(versioned_items(changes));
+    };
+
+    let timeout = match params.timeout.as_deref().map(parse_timeout) {
+        None => DEFAULT_WAIT_TIMEOUT,
+        Some(Some(timeout)) => timeout.min(MAX_WAIT_TIMEOUT),
+        Some(None) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                "timeout should be like 30s or 500ms",
+            )
+                .into_response())
+        }
+    };
+    let changes = tokio::select! {
+        changes = service.wait_for_changes(table_id, after, timeout) => changes?,
+        // Same as timeout, client would poll again, most probably via other instance
+        _ = shutdown.cancelled() => None,
+    };
+    Ok(match changes {
+        Some(changes) => versioned_items(changes),
+        // 304 only makes sense for conditional request, otherwise there is just nothing to return yet
+        None if if_none_match(&headers, after) => not_modified(after),
+        None => (StatusCode::NO_CONTENT, version_headers(after)).into_response(),
+    })
+}
+
+#[derive(Deserialize)]
+struct ChangesParams {
+    #[serde(default)]
+    since: TableVersion,
+}
+
+async fn changes_since<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Path(table_id): Path<i32>,
+    Query(params): Query<ChangesParams>,
+) -> Result<Json<ItemChanges>, ErrorResponse<S::Error>> {
+    Ok(Json(
+        service.changes_since(table_id.into(), params.since).await?,
+    ))
+}
+
+/// Reading single item costs same as checking version, so unlike list it's read along with version
+/// 404 has version too, so that client could tell when item could have appeared
+async fn get_item<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Path((table_id, item_id)): Path<(i32, i32)>,
+    headers: HeaderMap,
+) -> Result<Response, ErrorResponse<S::Error>> {
+    let (item, version) = service
+        .get_versioned_item(table_id.into(), item_id.into())
+        .await?;
+    Ok(match item {
+        Some(_) if if_none_match(&headers, version) => not_modified(version),
+        Some(item) => (version_headers(version), Json(item)).into_response(),
+        None => (StatusCode::NOT_FOUND, version_headers(version)).into_response(),
+    })
+}
+
+async fn advance_item<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Path((table_id, item_id)): Path<(i32, i32)>,
+    Json(status): Json<ItemStatus>,
+) -> Result<Json<ItemInfo>, ErrorResponse<S::Error>> {
+    Ok(Json(
+        service
+            .advance_item(table_id.into(), item_id.into(), status)
+            .await?,
+    ))
+}
+
+async fn add_menu_items<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Json(items): Json<Vec<NewMenuItem>>,
+) -> Result<Response, ErrorResponse<S::Error>> {
+    let items = service.add_menu_items(items.into_iter()).await?;
+    Ok((StatusCode::CREATED, Json(items)).into_response())
+}
+
+async fn list_menu<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+) -> Result<Json<Vec<MenuItem>>, ErrorResponse<S::Error>> {
+    Ok(Json(service.list_menu().await?))
+}
+
+async fn set_menu_item_available<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Path(menu_item_id): Path<i32>,
+    Json(available): Json<bool>,
+) -> Result<Json<MenuItem>, ErrorResponse<S::Error>> {
+    Ok(Json(
+        service
+            .set_menu_item_available(menu_item_id.into(), available)
+            .await?,
+    ))
+}
+
+async fn add_tables<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Json(tables): Json<Vec<NewTable>>,
+) -> Result<Response, ErrorResponse<S::Error>> {
+    let tables = service.add_tables(tables.into_iter()).await?;
+    Ok((StatusCode::CREATED, Json(tables)).into_response())
+}
+
+async fn list_tables<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+) -> Result<Json<Vec<Table>>, ErrorResponse<S::Error>> {
+    Ok(Json(service.list_tables().await?))
+}
+
+async fn get_table<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Path(table_id): Path<i32>,
+) -> Result<Response, ErrorResponse<S::Error>> {
+    Ok(match service.get_table(table_id.into()).await? {
+        Some(table) => Json(table).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+async fn set_table_active<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Path(table_id): Path<i32>,
+    Json(active): Json<bool>,
+) -> Result<Json<Table>, ErrorResponse<S::Error>> {
+    Ok(Json(
+        service.set_table_active(table_id.into(), active).await?,
+    ))
+}
+
+/// Each `ItemEvent` goes as JSON in data of SSE event
+fn event_stream(
+    events: ItemEvents,
+    shutdown: CancellationToken,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let events = events
+        .map(|event| Event::default().json_data(event))
+        .take_until(shutdown.cancelled_owned());
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+async fn events<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Extension(shutdown): Extension<CancellationToken>,
+) -> Result<Response, ErrorResponse<S::Error>> {
+    let events = service.subscribe(None).await?;
+    Ok(event_stream(events, shutdown).into_response())
+}
+
+async fn table_events<S: RestaurantService>(
+    State(service): State<Arc<S>>,
+    Path(table_id): Path<i32>,
+    Extension(shutdown): Extension<CancellationToken>,
+) -> Result<Response, ErrorResponse<S::Error>> {
+    let events = service.subscribe(Some(table_id.into())).await?;
+    Ok(event_stream(events, shutdown).into_response())
+}
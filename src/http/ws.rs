@@ -0,0 +1,685 @@
+//! WebSocket protocol for tablets, served at `GET /tables/{table_id}/ws`
+//!
+//! Connection for unregistered table is refused with 404 instead of upgrade.
+//! Tablet sends `WsRequest`s as JSON text messages, and gets `WsMessage::Response` or
+//! `WsMessage::Error` with same `request_id` for each of them, in order of requests.
+//! Meanwhile server pushes `WsMessage::Event`s for the table of connection.
+//!
+//! Tablet should remember `event_id` of last event it has seen, and pass it on reconnect as
+//! `?last_event_id=`, then it gets events it has missed before live ones. When that's not possible,
+//! e.g. too many events have passed, or tablet connected to other instance, it gets
+//! `WsMessage::Resync` instead, and should list items again.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::ws::{Message, Utf8Bytes, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
+
+use super::server::{log_error, ErrorResponse};
+use crate::service::{ErrorKind, ItemEvents, NewItem, RestaurantService};
+use crate::storage::model::{
+    IdempotencyKey, ItemEvent, ItemId, ItemInfo, ItemInfoShort, RemovedItems, TableId,
+};
+
+/// How many recent events instance keeps for tablets to resume from
+const EVENT_LOG_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WsRequest {
+    /// Chosen by tablet, repeated in response
+    pub request_id: String,
+    #[serde(flatten)]
+    pub command: WsCommand,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum WsCommand {
+    Add {
+        items: Vec<NewItem>,
+        #[serde(default)]
+        idempotency_key: Option<IdempotencyKey>,
+    },
+    Remove {
+        item_ids: Vec<ItemId>,
+    },
+    List,
+    Get {
+        item_id: ItemId,
+    },
+}
+
+/// Same as responses of matching HTTP routes
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WsResult {
+    Added(Vec<ItemInfo>),
+    Removed(RemovedItems),
+    Items(Vec<ItemInfoShort>),
+    Item(Option<ItemInfo>),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsMessage {
+    Response {
+        request_id: String,
+        #[serde(flatten)]
+        result: WsResult,
+    },
+    /// Request id is missing if request could not be parsed at all
+    Error {
+        request_id: Option<String>,
+        kind: ErrorKind,
+        message: String,
+    },
+    Event {
+        event_id: EventId,
+        event: ItemEvent,
+    },
+    /// Some events were missed, tablet should list items again
+    Resync,
+}
+
+/// Position of event in `EventLog`, opaque to tablets
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct EventId {
+    /// Changes each time log starts over, so ids from before could not be confused with new ones
+    epoch: u32,
+    seq: u64,
+}
+
+impl fmt::Display for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}-{}", self.epoch, self.seq)
+    }
+}
+
+impl FromStr for EventId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid event id {s:?}");
+        let (epoch, seq) = s.split_once('-').ok_or_else(invalid)?;
+        Ok(EventId {
+            epoch: u32::from_str_radix(epoch, 16).map_err(|_| invalid())?,
+            seq: seq.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl From<EventId> for String {
+    fn from(id: EventId) -> Self {
+        id.to_string()
+    }
+}
+
+impl TryFrom<String> for EventId {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+type LoggedEvent = (EventId, ItemEvent);
+
+/// Recent events of all tables, numbered in order they were seen by this instance
+pub(super) struct EventLog {
+    state: Mutex<EventLogState>,
+}
+
+struct EventLogState {
+    epoch: u32,
+    next_seq: u64,
+    /// Ordered by seq, without gaps
+    recent: VecDeque<(u64, ItemEvent)>,
+    live: broadcast::Sender<LoggedEvent>,
+}
+
+impl EventLogState {
+    fn new() -> EventLogState {
+        EventLogState {
+            epoch: rand::random(),
+            next_seq: 0,
+            recent: VecDeque::new(),
+            live: broadcast::channel(EVENT_LOG_CAPACITY).0,
+        }
+    }
+
+    /// Events of table after given one, or None if some of them are not kept
+    fn events_after(&self, table_id: &TableId, after: EventId) -> Option<Vec<LoggedEvent>> {
+        let oldest_seq = self.recent.front().map_or(self.next_seq, |(seq, _)| *seq);
+        if after.epoch != self.epoch || after.seq + 1 < oldest_seq || after.seq >= self.next_seq {
+            return None;
+        }
+        Some(
+            self.recent
+                .iter()
+                .filter(|(seq, event)| *seq > after.seq && event.table_id() == table_id)
+                .map(|(seq, event)| {
+                    let id = EventId {
+                        epoch: self.epoch,
+                        seq: *seq,
+                    };
+                    (id, event.clone())
+                })
+                .collect(),
+        )
+    }
+}
+
+impl EventLog {
+    fn new() -> EventLog {
+        EventLog {
+            state: Mutex::new(EventLogState::new()),
+        }
+    }
+
+    /// Forget everything, when some events could be missing from now on
+    /// Live receivers are closed, so tablets know about it as well
+    fn restart(&self) {
+        *self.state.lock().unwrap() = EventLogState::new();
+    }
+
+    fn push(&self, event: ItemEvent) {
+        let mut state = self.state.lock().unwrap();
+        let id = EventId {
+            epoch: state.epoch,
+            seq: state.next_seq,
+        };
+        state.next_seq += 1;
+        if state.recent.len() == EVENT_LOG_CAPACITY {
+            state.recent.pop_front();
+        }
+        state.recent.push_back((id.seq, event.clone()));
+        // Fails only when nobody is subscribed
+        let _ = state.live.send((id, event));
+    }
+
+    /// Events of table after `last_event_id` if it is given, and receiver of further events of
+    /// all tables, without any gap in between
+    fn subscribe(
+        &self,
+        table_id: &TableId,
+        last_event_id: Option<EventId>,
+    ) -> (Option<Vec<LoggedEvent>>, broadcast::Receiver<LoggedEvent>) {
+        let state = self.state.lock().unwrap();
+        let missed = match last_event_id {
+            Some(id) => state.events_after(table_id, id),
+            None => Some(vec![]),
+        };
+        (missed, state.live.subscribe())
+    }
+}
+
+/// Starts feeding log with events of service, until shutdown
+pub(super) async fn start_event_log<S>(
+    service: Arc<S>,
+    shutdown: CancellationToken,
+) -> Result<Arc<EventLog>, S::Error>
+where
+    S: RestaurantService + Send + Sync + 'static,
+{
+    let events = service.subscribe(None).await?;
+    let log = Arc::new(EventLog::new());
+    tokio::spawn(feed_event_log(service, log.clone(), events, shutdown));
+    Ok(log)
+}
+
+async fn feed_event_log<S: RestaurantService>(
+    service: Arc<S>,
+    log: Arc<EventLog>,
+    mut events: ItemEvents,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let event = tokio::select! {
+            event = events.next() => event,
+            _ = shutdown.cancelled() => return,
+        };
+        if let Some(event) = event {
+            log.push(event);
+            continue;
+        }
+
+        warn!("Item events subscription ended, starting event log over");
+        events = loop {
+            match service.subscribe(None).await {
+                Ok(events) => break events,
+                Err(error) => error!(%error, "Failed to subscribe to item events"),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(1)) => {},
+                _ = shutdown.cancelled() => return,
+            }
+        };
+        log.restart();
+    }
+}
+
+#[derive(Deserialize)]
+pub(super) struct WsParams {
+    last_event_id: Option<EventId>,
+}
+
+pub(super) async fn table_ws<S>(
+    State(service): State<Arc<S>>,
+    Path(table_id): Path<i32>,
+    Query(params): Query<WsParams>,
+    Extension(log): Extension<Arc<EventLog>>,
+    Extension(shutdown): Extension<CancellationToken>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ErrorResponse<S::Error>>
+where
+    S: RestaurantService + Send + Sync + 'static,
+{
+    let table_id: TableId = table_id.into();
+    if service.get_table(table_id.clone()).await?.is_none() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    Ok(ws.on_upgrade(move |socket| async move {
+        let tablet = serve_tablet(&*service, &log, &table_id, params, socket, shutdown);
+        if let Err(error) = tablet.await {
+            debug!(%error, %table_id, "Tablet connection failed");
+        }
+    }))
+}
+
+async fn send(socket: &mut WebSocket, message: &WsMessage) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).expect("Messages are always serializable");
+    socket.send(Message::Text(Utf8Bytes::from(text))).await
+}
+
+async fn serve_tablet<S: RestaurantService>(
+    service: &S,
+    log: &EventLog,
+    table_id: &TableId,
+    params: WsParams,
+    mut socket: WebSocket,
+    shutdown: CancellationToken,
+) -> Result<(), axum::Error> {
+    let (missed, mut live) = log.subscribe(table_id, params.last_event_id);
+    match missed {
+        Some(missed) => {
+            for (event_id, event) in missed {
+                send(&mut socket, &WsMessage::Event { event_id, event }).await?;
+            }
+        }
+        None => send(&mut socket, &WsMessage::Resync).await?,
+    }
+
+    loop {
+        tokio::select! {
+            message = socket.recv() => match message.transpose()? {
+                Some(Message::Text(text)) => {
+                    let reply = handle_request(service, table_id, &text).await;
+                    send(&mut socket, &reply).await?;
+                }
+                // Pings are answered by axum
+                Some(Message::Binary(_) | Message::Ping(_) | Message::Pong(_)) => {}
+                Some(Message::Close(_)) | None => return Ok(()),
+            },
+            event = live.recv() => match event {
+                Ok((event_id, event)) => {
+                    if event.table_id() == table_id {
+                        send(&mut socket, &WsMessage::Event { event_id, event }).await?;
+                    }
+                }
+                // Receiver skips missed events and goes on with next ones
+                Err(RecvError::Lagged(_)) => send(&mut socket, &WsMessage::Resync).await?,
+                // Log has started over
+                Err(RecvError::Closed) => {
+                    send(&mut socket, &WsMessage::Resync).await?;
+                    live = log.subscribe(table_id, None).1;
+                }
+            },
+            _ = shutdown.cancelled() => {
+                socket.send(Message::Close(None)).await?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn handle_request<S: RestaurantService>(
+    service: &S,
+    table_id: &TableId,
+    text: &str,
+) -> WsMessage {
+    let request: WsRequest = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(error) => {
+            return WsMessage::Error {
+                request_id: None,
+                kind: ErrorKind::Invalid,
+                message: error.to_string(),
+            }
+        }
+    };
+
+    let table_id = table_id.clone();
+    let result = match request.command {
+        WsCommand::Add {
+            items,
+            idempotency_key,
+        } => service
+            .add_items(table_id, idempotency_key, items.into_iter())
+            .await
+            .map(WsResult::Added),
+        WsCommand::Remove { item_ids } => service
+            .remove_items(table_id, item_ids.into_iter())
+            .await
+            .map(WsResult::Removed),
+        WsCommand::List => service.list_items(table_id).await.map(WsResult::Items),
+        WsCommand::Get { item_id } => service
+            .get_item(table_id, item_id)
+            .await
+            .map(WsResult::Item),
+    };
+
+    match result {
+        Ok(result) => WsMessage::Response {
+            request_id: request.request_id,
+            result,
+        },
+        Err(error) => WsMessage::Error {
+            request_id: Some(request.request_id),
+            kind: log_error(&error),
+            message: error.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_util::SinkExt;
+    use serde_json::json;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::Message as ClientMessage;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    use crate::forecast::HistoricalForecaster;
+    use crate::http::server::router;
+    use crate::service::DefaultRestaurantService;
+    use crate::storage::memory::SimpleMemoryStorage;
+    use crate::storage::model::{MenuItemId, NewMenuItem, NewTable};
+
+    type TestService = DefaultRestaurantService<SimpleMemoryStorage, HistoricalForecaster>;
+
+    struct TestServer {
+        service: Arc<TestService>,
+        addr: String,
+        table_id: TableId,
+        other_table_id: TableId,
+        menu_item_id: MenuItemId,
+    }
+
+    impl TestServer {
+        async fn start() -> TestServer {
+            let service = Arc::new(DefaultRestaurantService::new(
+                SimpleMemoryStorage::default(),
+                HistoricalForecaster::default(),
+            ));
+            let tables = service
+                .add_tables(["T1", "T2"].into_iter().map(|name| NewTable {
+                    name: name.into(),
+                    seats: 4,
+                    section: "test section".into(),
+                }))
+                .await
+                .unwrap();
+            let menu = service
+                .add_menu_items(
+                    [NewMenuItem {
+                        name: "test item".into(),
+                        category: "test category".into(),
+                        available: true,
+                    }]
+                    .into_iter(),
+                )
+                .await
+                .unwrap();
+
+            let router = router(service.clone(), CancellationToken::new())
+                .await
+                .unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+            TestServer {
+                service,
+                addr,
+                table_id: tables[0].table_id.clone(),
+                other_table_id: tables[1].table_id.clone(),
+                menu_item_id: menu[0].menu_item_id.clone(),
+            }
+        }
+
+        async fn connect(&self, last_event_id: Option<EventId>) -> Tablet {
+            let mut url = format!("ws://{}/tables/{}/ws", self.addr, self.table_id);
+            if let Some(id) = last_event_id {
+                url += &format!("?last_event_id={id}");
+            }
+            let (socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+            Tablet(socket)
+        }
+
+        async fn add_item(&self, table_id: &TableId) -> ItemInfo {
+            let item = NewItem {
+                menu_item_id: self.menu_item_id.clone(),
+                comment: "".into(),
+            };
+            let added = self
+                .service
+                .add_items(table_id.clone(), None, [item].into_iter());
+            added.await.unwrap().remove(0)
+        }
+    }
+
+    struct Tablet(WebSocketStream<MaybeTlsStream<TcpStream>>);
+
+    impl Tablet {
+        async fn send(&mut self, request: serde_json::Value) {
+            let text = request.to_string();
+            self.0.send(ClientMessage::text(text)).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> WsMessage {
+            let message = tokio::time::timeout(Duration::from_secs(5), self.0.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            serde_json::from_str(message.to_text().unwrap()).unwrap()
+        }
+
+        /// Response to a change comes along with event of it, in any order
+        async fn recv_with_event(&mut self) -> (WsMessage, ItemEvent) {
+            match (self.recv().await, self.recv().await) {
+                (WsMessage::Event { event, .. }, response)
+                | (response, WsMessage::Event { event, .. }) => (response, event),
+                other => panic!("expected response and event, got {other:?}"),
+            }
+        }
+
+        async fn recv_event(&mut self) -> (EventId, ItemEvent) {
+            match self.recv().await {
+                WsMessage::Event { event_id, event } => (event_id, event),
+                other => panic!("expected event, got {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_commands() {
+        let server = TestServer::start().await;
+        let mut tablet = server.connect(None).await;
+
+        let request = json!({
+            "request_id": "add",
+            "command": "add",
+            "items": [{"menu_item_id": server.menu_item_id, "comment": "ws item"}],
+        });
+        tablet.send(request).await;
+        let (response, event) = tablet.recv_with_event().await;
+        let WsMessage::Response {
+            request_id,
+            result: WsResult::Added(added),
+        } = response
+        else {
+            panic!("unexpected response {response:?}");
+        };
+        assert_eq!(request_id, "add");
+        assert_eq!(added.len(), 1);
+        assert_eq!(event, ItemEvent::Added(added[0].clone()));
+        let item_id = added[0].item_id.clone();
+
+        tablet
+            .send(json!({"request_id": "list", "command": "list"}))
+            .await;
+        match tablet.recv().await {
+            WsMessage::Response {
+                request_id,
+                result: WsResult::Items(items),
+            } if request_id == "list" => assert_eq!(items[0].item_id, item_id),
+            other => panic!("unexpected response {other:?}"),
+        }
+
+        let request = json!({"request_id": "get", "command": "get", "item_id": item_id});
+        tablet.send(request).await;
+        match tablet.recv().await {
+            WsMessage::Response {
+                request_id,
+                result: WsResult::Item(item),
+            } if request_id == "get" => assert_eq!(item.unwrap().comment, "ws item"),
+            other => panic!("unexpected response {other:?}"),
+        }
+
+        // Changes of other tables are not pushed
+        server.add_item(&server.other_table_id).await;
+        let request = json!({"request_id": "remove", "command": "remove", "item_ids": [item_id]});
+        tablet.send(request).await;
+        let (response, event) = tablet.recv_with_event().await;
+        match response {
+            WsMessage::Response {
+                request_id,
+                result: WsResult::Removed(removed),
+            } if request_id == "remove" => assert_eq!(removed.removed, vec![item_id.clone()]),
+            other => panic!("unexpected response {other:?}"),
+        }
+        let expected = ItemEvent::Removed {
+            table_id: server.table_id.clone(),
+            item_id,
+        };
+        assert_eq!(event, expected);
+
+        let request = json!({
+            "request_id": "invalid",
+            "command": "add",
+            "items": [{"menu_item_id": 1000, "comment": ""}],
+        });
+        tablet.send(request).await;
+        match tablet.recv().await {
+            WsMessage::Error {
+                request_id, kind, ..
+            } => {
+                assert_eq!(request_id.as_deref(), Some("invalid"));
+                assert_eq!(kind, ErrorKind::Invalid);
+            }
+            other => panic!("unexpected response {other:?}"),
+        }
+
+        tablet.send(json!({"command": "list"})).await;
+        match tablet.recv().await {
+            WsMessage::Error {
+                request_id, kind, ..
+            } => {
+                assert_eq!(request_id, None);
+                assert_eq!(kind, ErrorKind::Invalid);
+            }
+            other => panic!("unexpected response {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_resume() {
+        let server = TestServer::start().await;
+        let mut tablet = server.connect(None).await;
+        // Connection is subscribed once it serves requests
+        tablet
+            .send(json!({"request_id": "list", "command": "list"}))
+            .await;
+        tablet.recv().await;
+        let first = server.add_item(&server.table_id).await;
+        let (first_id, event) = tablet.recv_event().await;
+        assert_eq!(event, ItemEvent::Added(first));
+        drop(tablet);
+
+        // Missed while disconnected
+        server.add_item(&server.other_table_id).await;
+        let second = server.add_item(&server.table_id).await;
+
+        let mut tablet = server.connect(Some(first_id)).await;
+        let (second_id, event) = tablet.recv_event().await;
+        assert_eq!(event, ItemEvent::Added(second));
+        assert_ne!(second_id, first_id);
+        // Then live ones
+        let third = server.add_item(&server.table_id).await;
+        let (third_id, event) = tablet.recv_event().await;
+        assert_eq!(event, ItemEvent::Added(third));
+
+        // Nothing is missed since last one
+        let mut tablet = server.connect(Some(third_id)).await;
+        let fourth = server.add_item(&server.table_id).await;
+        assert_eq!(tablet.recv_event().await.1, ItemEvent::Added(fourth));
+
+        // Id from the future, or from other instance, can't be resumed from
+        let unknown = EventId {
+            seq: first_id.seq + 1000,
+            ..first_id
+        };
+        let mut tablet = server.connect(Some(unknown)).await;
+        assert!(matches!(tablet.recv().await, WsMessage::Resync));
+    }
+
+    #[tokio::test]
+    async fn test_ws_unknown_table() {
+        let server = TestServer::start().await;
+        let url = format!("ws://{}/tables/{}/ws", server.addr, 1000);
+        match tokio_tungstenite::connect_async(url).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 404)
+            }
+            other => panic!("expected HTTP error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_event_id_format() {
+        let id = EventId {
+            epoch: 0xdeadbeef,
+            seq: 42,
+        };
+        assert_eq!(id.to_string(), "deadbeef-42");
+        assert_eq!("deadbeef-42".parse(), Ok(id));
+        assert!("deadbeef".parse::<EventId>().is_err());
+        assert!("x-42".parse::<EventId>().is_err());
+    }
+}
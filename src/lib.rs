@@ -0,0 +1,5 @@
+pub mod cli;
+pub mod forecast;
+pub mod http;
+pub mod service;
+pub mod storage;
@@ -1,56 +1,161 @@
-mod service;
-mod storage;
-
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::anyhow;
-use clap::Parser;
-use deadpool_postgres::PoolConfig;
+use clap::{Parser, Subcommand};
+use reqwest::Url;
 use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
-
-use crate::service::{DefaultRestaurantService, RestaurantService};
-use crate::storage::pg::PostgresStorage;
+use tracing::{error, info, warn};
+
+use paidy_restaurant_api::cli::{init_tracing, PostgresArgs};
+use paidy_restaurant_api::forecast::{
+    Forecaster, HistoricalForecaster, HistoricalForecasterConfig,
+};
+use paidy_restaurant_api::http::client::HttpRestaurantClient;
+use paidy_restaurant_api::http::server::serve;
+use paidy_restaurant_api::service::{
+    DefaultRestaurantService, ErrorKind, RestaurantService, ServiceError,
+};
+use paidy_restaurant_api::storage::model::{MenuItemId, NewMenuItem, NewTable, Storage, TableId};
+use paidy_restaurant_api::storage::pg::{
+    PostgresStorage, RetryPolicy, RetryStats, RetryStatsSnapshot,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Postgres host
-    #[arg(long)]
-    postgres_host: String,
+    #[command(subcommand)]
+    mode: Mode,
+}
 
-    /// Postgres host
-    #[arg(long, default_value_t = 5432)]
-    postgres_port: u16,
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Serve HTTP API and/or run load simulator in-process, on top of PostgreSQL storage
+    Local(LocalArgs),
+    /// Run load simulator against remote deployment over HTTP API
+    Loadgen(LoadgenArgs),
+}
 
-    /// Postgres host
-    #[arg(long, env)]
-    postgres_username: String,
+#[derive(clap::Args, Debug)]
+struct LocalArgs {
+    #[command(flatten)]
+    postgres: PostgresArgs,
 
-    /// Postgres host
-    #[arg(long, env)]
-    postgres_password: String,
+    /// Count of load generating tasks
+    #[arg(long, default_value_t = 0)]
+    tasks: usize,
 
-    /// Postgres host
-    #[arg(long)]
-    postgres_database: String,
+    /// Count of tables used by load generating tasks, lower count means more contention
+    #[arg(long, default_value_t = 10)]
+    tables: usize,
+
+    /// Attempts for each storage transaction, 1 disables retries of serialization failures
+    #[arg(long, default_value_t = RetryPolicy::default().max_attempts)]
+    max_attempts: u32,
+
+    /// How many items kitchen cooks at the same time, used to forecast time items wait in queue
+    #[arg(long, default_value_t = HistoricalForecasterConfig::default().stations)]
+    kitchen_stations: u32,
+
+    /// Serve HTTP API
+    #[arg(long, default_value_t = false)]
+    serve: bool,
+
+    /// Address to listen for HTTP connections
+    #[arg(long, default_value = "0.0.0.0:8080")]
+    listen: SocketAddr,
+}
 
-    /// Postgres connections pool size
+#[derive(clap::Args, Debug)]
+struct LoadgenArgs {
+    /// Base URL of HTTP API, e.g. load balancer in front of app instances
     #[arg(long)]
-    postgres_pool: usize,
+    url: Url,
 
     /// Count of load generating tasks
     #[arg(long)]
     tasks: usize,
 
-    // This should be separate migrator executable
-    /// Run DB initialization and exit
-    #[arg(long, default_value_t = false)]
-    init_and_exit: bool,
+    /// Count of tables used by load generating tasks, lower count means more contention
+    #[arg(long, default_value_t = 10)]
+    tables: usize,
 }
 
-async fn load_simulator_task<S>(service: Arc<S>, token: CancellationToken) -> anyhow::Result<()>
+/// Menu for load simulator to order from, adds a small one if there's none yet
+/// Returns ids of menu items that are available for ordering
+async fn ensure_menu<S>(service: &S) -> anyhow::Result<Vec<MenuItemId>>
+where
+    S: RestaurantService,
+    S::Error: Send + Sync + 'static,
+{
+    let mut menu = service.list_menu().await?;
+    if menu.is_empty() {
+        let items = [
+            ("ramen", "mains"),
+            ("udon", "mains"),
+            ("gyoza", "sides"),
+            ("edamame", "sides"),
+            ("green tea", "drinks"),
+            ("beer", "drinks"),
+        ]
+        .into_iter()
+        .map(|(name, category)| NewMenuItem {
+            name: name.into(),
+            category: category.into(),
+            available: true,
+        });
+        menu = service.add_menu_items(items).await?;
+        info!(items = menu.len(), "Added menu for load simulator");
+    }
+    let available = menu
+        .into_iter()
+        .filter(|item| item.available)
+        .map(|item| item.menu_item_id)
+        .collect::<Vec<_>>();
+    if available.is_empty() {
+        return Err(anyhow!("Nothing on the menu is available"));
+    }
+    Ok(available)
+}
+
+/// Active tables for load simulator to use, registers new ones if there's not enough
+async fn ensure_tables<S>(service: &S, count: usize) -> anyhow::Result<Vec<TableId>>
+where
+    S: RestaurantService,
+    S::Error: Send + Sync + 'static,
+{
+    let mut tables = service
+        .list_tables()
+        .await?
+        .into_iter()
+        .filter(|table| table.active)
+        .map(|table| table.table_id)
+        .collect::<Vec<_>>();
+    if tables.len() < count {
+        let new_tables = (tables.len()..count).map(|idx| NewTable {
+            name: format!("Simulated {idx}"),
+            seats: 4,
+            section: "simulator".into(),
+        });
+        let added = service.add_tables(new_tables).await?;
+        info!(tables = added.len(), "Registered tables for load simulator");
+        tables.extend(added.into_iter().map(|table| table.table_id));
+    }
+    tables.truncate(count);
+    if tables.is_empty() {
+        return Err(anyhow!("Load simulator needs at least one table"));
+    }
+    Ok(tables)
+}
+
+async fn load_simulator_task<S>(
+    service: Arc<S>,
+    tables: Vec<TableId>,
+    menu: Vec<MenuItemId>,
+    token: CancellationToken,
+) -> anyhow::Result<()>
 where
     S: RestaurantService,
     S::Error: Send + Sync + 'static,
@@ -61,8 +166,9 @@ where
     use rand::seq::IteratorRandom;
     use rand::Rng;
 
-    use crate::service::NewItem;
-    use crate::storage::model::TableId;
+    use rand::seq::SliceRandom;
+
+    use paidy_restaurant_api::service::NewItem;
 
     let mut known_item_ids = HashSet::new();
 
@@ -78,23 +184,24 @@ where
             Remove,
             List,
             Get,
+            Advance,
         }
 
         // TODO should probably be Uniform instead of Standard
         impl Distribution<Op> for Standard {
             fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Op {
-                match rng.gen_range(0..=3) {
+                match rng.gen_range(0..=4) {
                     0 => Op::Add,
                     1 => Op::Remove,
                     2 => Op::List,
-                    _ => Op::Get,
+                    3 => Op::Get,
+                    _ => Op::Advance,
                 }
             }
         }
 
-        fn gen_table_id(rng: &mut impl Rng) -> TableId {
-            rng.gen_range(0..10).into()
-        }
+        let gen_table_id =
+            |rng: &mut rand::rngs::ThreadRng| -> TableId { tables.choose(rng).unwrap().clone() };
 
         let op: Op = {
             let mut rng = rand::thread_rng();
@@ -103,19 +210,33 @@ where
 
         match op {
             Op::Add => {
-                let (table_id, items) = {
+                let (table_id, idempotency_key, items) = {
                     let mut rng = rand::thread_rng();
                     let table_id = gen_table_id(&mut rng);
+                    // Same as real tablet would do: fresh key for each order
+                    let idempotency_key = format!("{:016x}", rng.gen::<u64>()).into();
                     let item_count = rng.gen_range(0..10);
-                    let items = (0..item_count).map(|_| NewItem {
-                        name: "new item".into(),
-                        comment: "".into(),
-                    });
-                    (table_id, items)
+                    let items = (0..item_count)
+                        .map(|_| NewItem {
+                            menu_item_id: menu.choose(&mut rng).unwrap().clone(),
+                            comment: "".into(),
+                        })
+                        .collect::<Vec<_>>();
+                    (table_id, idempotency_key, items)
                 };
 
-                info!(?table_id, "Adding items");
-                service.add_items(table_id, items.into_iter()).await?;
+                info!(?table_id, ?idempotency_key, "Adding items");
+                let items = match service
+                    .add_items(table_id, Some(idempotency_key), items.into_iter())
+                    .await
+                {
+                    Ok(items) => items,
+                    Err(error) => {
+                        tolerate_internal(error)?;
+                        continue;
+                    }
+                };
+                known_item_ids.extend(items.into_iter().map(|i| i.item_id));
             }
             Op::Remove => {
                 let (table_id, item_ids) = {
@@ -133,7 +254,16 @@ where
                     known_item_ids.remove(item_id);
                 }
                 info!(?table_id, ?item_ids, "Removing items");
-                service.remove_items(table_id, item_ids.into_iter()).await?;
+                let result = match service.remove_items(table_id, item_ids.into_iter()).await {
+                    Ok(result) => result,
+                    Err(error) => {
+                        tolerate_internal(error)?;
+                        continue;
+                    }
+                };
+                if !result.not_found.is_empty() {
+                    info!(not_found = ?result.not_found, "Some items were already removed");
+                }
             }
             Op::List => {
                 let table_id = {
@@ -141,7 +271,13 @@ where
                     gen_table_id(&mut rng)
                 };
                 info!(?table_id, "Listing items");
-                let items = service.list_items(table_id).await?;
+                let items = match service.list_items(table_id).await {
+                    Ok(items) => items,
+                    Err(error) => {
+                        tolerate_internal(error)?;
+                        continue;
+                    }
+                };
                 known_item_ids.extend(items.into_iter().map(|i| i.item_id));
             }
             Op::Get => {
@@ -157,67 +293,252 @@ where
                     (table_id, item_id)
                 };
                 info!(?table_id, ?item_id, "Reading item");
-                service.get_item(table_id, item_id).await?;
+                if let Err(error) = service.get_item(table_id, item_id).await {
+                    tolerate_internal(error)?;
+                }
+            }
+            Op::Advance => {
+                let table_id = {
+                    let mut rng = rand::thread_rng();
+                    gen_table_id(&mut rng)
+                };
+                let items = match service.list_items(table_id.clone()).await {
+                    Ok(items) => items,
+                    Err(error) => {
+                        tolerate_internal(error)?;
+                        continue;
+                    }
+                };
+                let item = {
+                    let mut rng = rand::thread_rng();
+                    items
+                        .into_iter()
+                        .filter_map(|item| item.status.next().map(|to| (item.item_id, to)))
+                        .choose(&mut rng)
+                };
+                let (item_id, to) = match item {
+                    None => continue,
+                    Some(item) => item,
+                };
+                info!(?table_id, ?item_id, %to, "Advancing item");
+                match service.advance_item(table_id, item_id, to).await {
+                    Ok(_) => {}
+                    // Other task could remove or advance same item after list_items
+                    Err(e) if e.kind() != ErrorKind::Internal => {
+                        info!(error = %e, "Item was changed concurrently");
+                    }
+                    Err(e) => tolerate_internal(e)?,
+                }
             }
         }
     }
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    {
-        use tracing_subscriber::{fmt, prelude::*, EnvFilter};
-        tracing_subscriber::registry()
-            .with(fmt::layer().pretty())
-            .with(EnvFilter::from_default_env())
-            .init();
+/// Storage errors, e.g. transactions that ran out of retries with `--max-attempts 1`, are expected
+/// under contention, so simulator keeps going, and retry stats show how many there were
+/// Other errors mean simulator itself is wrong
+fn tolerate_internal<E>(error: E) -> anyhow::Result<()>
+where
+    E: ServiceError + Send + Sync + 'static,
+{
+    if error.kind() != ErrorKind::Internal {
+        return Err(error.into());
     }
+    warn!(%error, "Operation failed");
+    Ok(())
+}
 
-    let args = Args::parse();
+/// Periodically log retry counters, to see how much contention there is
+async fn report_retry_stats(
+    stats: Arc<RetryStats>,
+    token: CancellationToken,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = token.cancelled() => break,
+        }
+        let RetryStatsSnapshot {
+            transactions,
+            retries,
+            exhausted,
+        } = stats.snapshot();
+        info!(transactions, retries, exhausted, "Transaction retry stats");
+    }
+    Ok(())
+}
 
-    let pool = {
-        use deadpool_postgres::tokio_postgres::NoTls;
-        use deadpool_postgres::Config;
-
-        let mut cfg = Config::new();
-        cfg.host = Some(args.postgres_host);
-        cfg.port = Some(args.postgres_port);
-        cfg.user = Some(args.postgres_username);
-        cfg.password = Some(args.postgres_password);
-        cfg.dbname = Some(args.postgres_database);
-        cfg.pool = Some(PoolConfig {
-            max_size: args.postgres_pool,
-            ..Default::default()
-        });
-        let pool = cfg.create_pool(None, NoTls)?;
-        {
-            // Just to check connectivity
-            let db = pool.get().await?;
-            drop(db);
+/// Periodically delete idempotency keys that can not be replayed anymore,
+/// and removed items that `changes_since` does not report anymore
+async fn delete_expired(
+    storage: Arc<PostgresStorage>,
+    token: CancellationToken,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = token.cancelled() => break,
+        }
+        // Those would be deleted on next run, no reason to stop serving because of that
+        match storage.delete_expired_idempotency_keys().await {
+            Ok(deleted) => info!(deleted, "Deleted expired idempotency keys"),
+            Err(error) => warn!(%error, "Failed to delete expired idempotency keys"),
         }
-        pool
-    };
+        match storage.delete_expired_tombstones().await {
+            Ok(deleted) => info!(deleted, "Deleted expired removed items"),
+            Err(error) => warn!(%error, "Failed to delete expired removed items"),
+        }
+    }
+    Ok(())
+}
 
-    if args.init_and_exit {
-        storage::pg::init_db(&pool).await?;
-        return Ok(());
+/// Periodically feed forecaster with kitchen stats, so forecasts follow kitchen load,
+/// and move forecasts of items already ordered accordingly
+async fn refresh_forecaster<S, F>(
+    service: Arc<DefaultRestaurantService<S, F>>,
+    token: CancellationToken,
+) -> anyhow::Result<()>
+where
+    S: Storage,
+    S::Error: Send + Sync + 'static,
+    F: Forecaster,
+{
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = token.cancelled() => break,
+        }
+        // Other instances recalculate same forecasts concurrently, and could win all retries,
+        // that's fine as forecasts are up to date then anyway
+        let result = async {
+            service.refresh_forecaster().await?;
+            service.recalculate_forecasts().await
+        }
+        .await;
+        match result {
+            Ok(updated) => info!(updated, "Recalculated forecasts"),
+            Err(error) => warn!(%error, "Failed to recalculate forecasts"),
+        }
     }
+    Ok(())
+}
 
-    let storage = PostgresStorage::new(pool);
-    // let storage = storage::SimpleMemoryStorage::default();
-    let service = DefaultRestaurantService::new(storage);
-    let service = Arc::new(service);
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    init_tracing();
+
+    let args = Args::parse();
 
     let cancellation = CancellationToken::new();
     let mut set = JoinSet::new();
 
-    for _ in 0..args.tasks {
-        let service = service.clone();
-        let token = cancellation.child_token();
-        set.spawn(load_simulator_task(service, token));
+    match args.mode {
+        Mode::Local(args) => {
+            let pool = args.postgres.create_pool().await?;
+
+            if !args.serve && args.tasks == 0 {
+                return Err(anyhow!("Nothing to run, pass --serve and/or --tasks"));
+            }
+
+            let storage = PostgresStorage::new(pool)
+                .await?
+                .with_retry_policy(RetryPolicy {
+                    max_attempts: args.max_attempts,
+                    ..Default::default()
+                });
+            let storage = Arc::new(storage);
+            // let storage = storage::SimpleMemoryStorage::default();
+
+            {
+                let stats = storage.retry_stats();
+                let token = cancellation.child_token();
+                set.spawn(report_retry_stats(stats, token));
+            }
+            {
+                let storage = storage.clone();
+                let token = cancellation.child_token();
+                set.spawn(delete_expired(storage, token));
+            }
+            {
+                // Listening starts before serving, so subscribers do not miss anything
+                let listener = storage
+                    .clone()
+                    .listen_item_events(&args.postgres.pg_config()?)
+                    .await?;
+                let token = cancellation.child_token();
+                set.spawn(async move {
+                    listener.run(token).await;
+                    Ok(())
+                });
+            }
+
+            let forecaster = HistoricalForecaster::new(HistoricalForecasterConfig {
+                stations: args.kitchen_stations,
+                ..Default::default()
+            });
+            let service = DefaultRestaurantService::new(storage, forecaster);
+            let service = Arc::new(service);
+
+            {
+                let service = service.clone();
+                let token = cancellation.child_token();
+                set.spawn(refresh_forecaster(service, token));
+            }
+
+            if args.serve {
+                let service = service.clone();
+                let token = cancellation.child_token();
+                set.spawn(serve(service, args.listen, token));
+            }
+
+            let (tables, menu) = if args.tasks > 0 {
+                (
+                    ensure_tables(service.as_ref(), args.tables).await?,
+                    ensure_menu(service.as_ref()).await?,
+                )
+            } else {
+                (vec![], vec![])
+            };
+            for _ in 0..args.tasks {
+                let service = service.clone();
+                let tables = tables.clone();
+                let menu = menu.clone();
+                let token = cancellation.child_token();
+                set.spawn(load_simulator_task(service, tables, menu, token));
+            }
+        }
+        Mode::Loadgen(args) => {
+            if args.tasks == 0 {
+                return Err(anyhow!("Nothing to run, pass non-zero --tasks"));
+            }
+
+            // Single client for all tasks to share connection pool
+            let service = Arc::new(HttpRestaurantClient::new(args.url));
+
+            let tables = ensure_tables(service.as_ref(), args.tables).await?;
+            let menu = ensure_menu(service.as_ref()).await?;
+            for _ in 0..args.tasks {
+                let service = service.clone();
+                let tables = tables.clone();
+                let menu = menu.clone();
+                let token = cancellation.child_token();
+                set.spawn(load_simulator_task(service, tables, menu, token));
+            }
+        }
     }
 
+    run_until_interrupted(set, cancellation).await
+}
+
+/// Wait for Ctrl+C or for any task to stop, then stop and join all tasks
+async fn run_until_interrupted(
+    mut set: JoinSet<anyhow::Result<()>>,
+    cancellation: CancellationToken,
+) -> anyhow::Result<()> {
     let mut result = Ok(());
 
     let interrupt = tokio::signal::ctrl_c();
@@ -1,92 +1,337 @@
 use std::fmt::Debug;
+use std::pin::Pin;
 
 use async_trait::async_trait;
 use chrono::{Duration, Utc};
 use derive_more::From;
+use futures_util::{future, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio_stream::wrappers::BroadcastStream;
 use tracing::instrument;
 
+use crate::forecast::Forecaster;
 use crate::storage::model::{
-    ItemId, ItemInfo, ItemInfoShort, NewItem as StorageNewItem, Storage, TableId,
+    AdvancedItem, ForecastUpdate, IdempotencyKey, ItemChanges, ItemEvent, ItemId, ItemInfo,
+    ItemInfoShort, ItemStatus, MenuItem, MenuItemId, NewItem as StorageNewItem, NewMenuItem,
+    NewTable, RemovedItems, Storage, Table, TableId, TableVersion,
 };
 
+#[derive(Debug, Serialize, Deserialize)]
 pub struct NewItem {
-    pub name: String,
+    pub menu_item_id: MenuItemId,
     pub comment: String,
 }
 
+pub type ItemEvents = Pin<Box<dyn Stream<Item = ItemEvent> + Send>>;
+
+/// Coarse classification of service errors, so transport could report them properly
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Request refers to something that does not exist
+    NotFound,
+    /// Request is valid by itself, but not in current state
+    Conflict,
+    /// Request is invalid by itself
+    Invalid,
+    /// Anything else, there's nothing client can do about it
+    Internal,
+}
+
+pub trait ServiceError: std::error::Error {
+    fn kind(&self) -> ErrorKind;
+}
+
 /// Service implementation: this is the reflection of public service API in Rust
 /// It may or may not use Storage to actually persist any items.
 /// It may represent HTTP client as well as service implementation.
 #[async_trait]
 pub trait RestaurantService {
-    type Error: std::error::Error;
+    type Error: ServiceError;
 
+    /// Returns created items, in same order as passed
+    /// Fails with `ErrorKind::NotFound` if table is not registered, `ErrorKind::Conflict` if it
+    /// is not active, and `ErrorKind::Invalid` if any of items is not on the menu, or is not available
+    /// Repeated call with same idempotency key returns result of the first one instead of adding,
+    /// even if table or menu has changed since then
     async fn add_items(
         &self,
         table_id: TableId,
+        idempotency_key: Option<IdempotencyKey>,
         items: impl Iterator<Item = NewItem> + Send,
-    ) -> Result<(), Self::Error>;
+    ) -> Result<Vec<ItemInfo>, Self::Error>;
 
     async fn remove_items(
         &self,
         table_id: TableId,
         item_ids: impl Iterator<Item = ItemId> + Send,
-    ) -> Result<(), Self::Error>;
+    ) -> Result<RemovedItems, Self::Error>;
 
     async fn list_items(&self, table_id: TableId) -> Result<Vec<ItemInfoShort>, Self::Error>;
 
+    /// Items changed and removed since table was at given version, to keep copy of `list_items`
+    /// up to date, e.g. after tablet reconnects. Pass version from previous call as next cursor,
+    /// and `TableVersion::default()` to start over
+    async fn changes_since(
+        &self,
+        table_id: TableId,
+        since: TableVersion,
+    ) -> Result<ItemChanges, Self::Error>;
+
+    /// Current version of table, cheap check whether anything has changed since `changes_since`
+    async fn table_version(&self, table_id: TableId) -> Result<TableVersion, Self::Error>;
+
     async fn get_item(
         &self,
         table_id: TableId,
         item_id: ItemId,
     ) -> Result<Option<ItemInfo>, Self::Error>;
+
+    /// Same as `get_item`, along with version of table, so client could ask for item only if
+    /// table has changed since
+    async fn get_versioned_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+    ) -> Result<(Option<ItemInfo>, TableVersion), Self::Error>;
+
+    /// Move item to next status, e.g. when kitchen starts cooking it
+    /// Fails with `ErrorKind::NotFound` for unknown item, and `ErrorKind::Conflict` if `to` is
+    /// not the next status of item
+    async fn advance_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+        to: ItemStatus,
+    ) -> Result<ItemInfo, Self::Error>;
+
+    /// Returns created menu items, in same order as passed
+    async fn add_menu_items(
+        &self,
+        items: impl Iterator<Item = NewMenuItem> + Send,
+    ) -> Result<Vec<MenuItem>, Self::Error>;
+
+    async fn list_menu(&self) -> Result<Vec<MenuItem>, Self::Error>;
+
+    /// Fails with `ErrorKind::NotFound` for unknown menu item
+    async fn set_menu_item_available(
+        &self,
+        menu_item_id: MenuItemId,
+        available: bool,
+    ) -> Result<MenuItem, Self::Error>;
+
+    /// Returns registered tables, in same order as passed
+    async fn add_tables(
+        &self,
+        tables: impl Iterator<Item = NewTable> + Send,
+    ) -> Result<Vec<Table>, Self::Error>;
+
+    async fn list_tables(&self) -> Result<Vec<Table>, Self::Error>;
+
+    /// Returns None if table is not registered
+    async fn get_table(&self, table_id: TableId) -> Result<Option<Table>, Self::Error>;
+
+    /// Fails with `ErrorKind::NotFound` for unknown table
+    async fn set_table_active(&self, table_id: TableId, active: bool)
+        -> Result<Table, Self::Error>;
+
+    /// Waits until version of table differs from `after`, then returns all of its items along with
+    /// new version, same as `changes_since` from scratch does. Returns `None` if table did not
+    /// change within `timeout`
+    /// Fails with `ErrorKind::NotFound` for unknown table
+    async fn wait_for_changes(
+        &self,
+        table_id: TableId,
+        after: TableVersion,
+        timeout: std::time::Duration,
+    ) -> Result<Option<ItemChanges>, Self::Error>;
+
+    /// Changes of items on a table, or on all tables when `table_id` is `None`, made after this call
+    /// Subscribe before listing items to not miss anything in between
+    /// Stream ends when subscriber falls too far behind, it should list items and subscribe again
+    /// Fails with `ErrorKind::NotFound` for unknown table
+    async fn subscribe(&self, table_id: Option<TableId>) -> Result<ItemEvents, Self::Error>;
 }
 
 #[derive(Debug, Error, From)]
 pub enum DefaultRestaurantServiceError<SE: std::error::Error> {
     #[error(transparent)]
     StorageError(SE),
+    #[error("item {0} not found")]
+    #[from(ignore)]
+    ItemNotFound(ItemId),
+    #[error("item can not go from {from} to {to}")]
+    #[from(ignore)]
+    IllegalTransition { from: ItemStatus, to: ItemStatus },
+    /// Unknown or unavailable menu items, without duplicates
+    #[error("menu items {0:?} can not be ordered")]
+    #[from(ignore)]
+    NotOnMenu(Vec<MenuItemId>),
+    #[error("menu item {0} not found")]
+    #[from(ignore)]
+    MenuItemNotFound(MenuItemId),
+    #[error("table {0} not found")]
+    #[from(ignore)]
+    TableNotFound(TableId),
+    #[error("table {0} is not active")]
+    #[from(ignore)]
+    TableInactive(TableId),
 }
 
-pub struct DefaultRestaurantService<S> {
+impl<SE: std::error::Error> ServiceError for DefaultRestaurantServiceError<SE> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            DefaultRestaurantServiceError::StorageError(_) => ErrorKind::Internal,
+            DefaultRestaurantServiceError::ItemNotFound(_) => ErrorKind::NotFound,
+            DefaultRestaurantServiceError::IllegalTransition { .. } => ErrorKind::Conflict,
+            DefaultRestaurantServiceError::NotOnMenu(_) => ErrorKind::Invalid,
+            DefaultRestaurantServiceError::MenuItemNotFound(_) => ErrorKind::NotFound,
+            DefaultRestaurantServiceError::TableNotFound(_) => ErrorKind::NotFound,
+            DefaultRestaurantServiceError::TableInactive(_) => ErrorKind::Conflict,
+        }
+    }
+}
+
+pub struct DefaultRestaurantService<S, F> {
     storage: S,
+    forecaster: F,
+}
+
+/// Forecast updates are not published as events, so waiters recheck version this often as well
+const WAIT_RECHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Forecasts that moved less than this are not worth a write
+fn forecast_update_threshold() -> Duration {
+    Duration::seconds(30)
+}
+
+impl<S, F> DefaultRestaurantService<S, F> {
+    pub fn new(storage: S, forecaster: F) -> DefaultRestaurantService<S, F> {
+        DefaultRestaurantService {
+            storage,
+            forecaster,
+        }
+    }
 }
 
-impl<S> DefaultRestaurantService<S> {
-    pub fn new(storage: S) -> DefaultRestaurantService<S> {
-        DefaultRestaurantService { storage }
+impl<S: Storage, F: Forecaster> DefaultRestaurantService<S, F> {
+    /// Feed forecaster with current kitchen stats, should be called periodically
+    /// Stats come from storage, so forecasts account for items added via all instances
+    pub async fn refresh_forecaster(&self) -> Result<(), DefaultRestaurantServiceError<S::Error>> {
+        let since = Utc::now() - self.forecaster.window();
+        let stats = self.storage.kitchen_stats(since).await?;
+        self.forecaster.update(stats);
+        Ok(())
     }
 
-    fn get_forecast() -> Duration {
-        // TODO either lift RNG instance up (e.g. to struct fields) or use proper forecasting
-        use rand::Rng;
-        let mut rng = rand::thread_rng();
-        let seconds = rng.gen_range(5 * 60..15 * 60);
-        Duration::seconds(seconds)
+    /// Re-forecast all items kitchen did not finish yet against current kitchen load,
+    /// should be called periodically after `refresh_forecaster`
+    /// Returns count of items which forecast changed noticeably
+    pub async fn recalculate_forecasts(
+        &self,
+    ) -> Result<u64, DefaultRestaurantServiceError<S::Error>> {
+        let now = Utc::now();
+        let items = self
+            .storage
+            .outstanding_items(now - self.forecaster.window())
+            .await?;
+        let forecasts = self.forecaster.forecast_outstanding(&items, now);
+
+        let updates = items
+            .into_iter()
+            .zip(forecasts)
+            .filter(|(item, forecast)| {
+                (*forecast - item.forecast_ready_at).abs() >= forecast_update_threshold()
+            })
+            .map(|(item, forecast)| ForecastUpdate {
+                table_id: item.table_id,
+                item_id: item.item_id,
+                forecast_ready_at: forecast,
+            })
+            .collect::<Vec<_>>();
+        if updates.is_empty() {
+            return Ok(0);
+        }
+        Ok(self.storage.update_forecasts(updates.into_iter()).await?)
     }
 }
 
 #[async_trait]
-impl<S: Storage + Send + Sync> RestaurantService for DefaultRestaurantService<S> {
+impl<S, F> RestaurantService for DefaultRestaurantService<S, F>
+where
+    S: Storage + Send + Sync,
+    F: Forecaster + Send + Sync,
+{
     type Error = DefaultRestaurantServiceError<S::Error>;
 
     #[instrument(skip(self, items))]
     async fn add_items(
         &self,
         table_id: TableId,
+        idempotency_key: Option<IdempotencyKey>,
         items: impl Iterator<Item = NewItem> + Send,
-    ) -> Result<(), Self::Error> {
+    ) -> Result<Vec<ItemInfo>, Self::Error> {
+        // Retry of an order that was already added should get same items, even if table or menu
+        // has changed since then. Storage replays key anyway, this only skips validation
+        if let Some(key) = &idempotency_key {
+            if let Some(items) = self
+                .storage
+                .get_idempotent_items(table_id.clone(), key.clone())
+                .await?
+            {
+                return Ok(items);
+            }
+        }
+
+        // Table and menu could change before items are added, but that is fine: order was valid
+        // when guest made it
+        match self.storage.get_table(table_id.clone()).await? {
+            None => return Err(DefaultRestaurantServiceError::TableNotFound(table_id)),
+            Some(table) if !table.active => {
+                return Err(DefaultRestaurantServiceError::TableInactive(table_id))
+            }
+            Some(_) => {}
+        }
+
+        let items = items.collect::<Vec<_>>();
+
+        let mut menu_item_ids = vec![];
+        for item in items.iter() {
+            if !menu_item_ids.contains(&item.menu_item_id) {
+                menu_item_ids.push(item.menu_item_id.clone());
+            }
+        }
+        let menu = self
+            .storage
+            .get_menu_items(menu_item_ids.clone().into_iter())
+            .await?;
+        let not_on_menu = menu_item_ids
+            .into_iter()
+            .filter(|id| !menu.iter().any(|m| &m.menu_item_id == id && m.available))
+            .collect::<Vec<_>>();
+        if !not_on_menu.is_empty() {
+            return Err(DefaultRestaurantServiceError::NotOnMenu(not_on_menu));
+        }
+
         let now = Utc::now();
         Ok(self
             .storage
             .add_items(
                 table_id,
-                items.map(|i| StorageNewItem {
-                    name: i.name,
-                    comment: i.comment,
-                    created_at: now,
-                    forecast_ready_at: now + Self::get_forecast(),
+                idempotency_key,
+                items.into_iter().map(|i| {
+                    let menu_item = menu
+                        .iter()
+                        .find(|m| m.menu_item_id == i.menu_item_id)
+                        .expect("Menu item was checked above");
+                    StorageNewItem {
+                        forecast_ready_at: now + self.forecaster.forecast(&i.menu_item_id),
+                        menu_item_id: i.menu_item_id,
+                        name: menu_item.name.clone(),
+                        comment: i.comment,
+                        created_at: now,
+                    }
                 }),
             )
             .await?)
@@ -97,7 +342,7 @@ impl<S: Storage + Send + Sync> RestaurantService for DefaultRestaurantService<S>
         &self,
         table_id: TableId,
         item_ids: impl Iterator<Item = ItemId> + Send,
-    ) -> Result<(), Self::Error> {
+    ) -> Result<RemovedItems, Self::Error> {
         Ok(self.storage.remove_items(table_id, item_ids).await?)
     }
 
@@ -106,6 +351,20 @@ impl<S: Storage + Send + Sync> RestaurantService for DefaultRestaurantService<S>
         Ok(self.storage.list_items(table_id).await?)
     }
 
+    #[instrument(skip(self))]
+    async fn changes_since(
+        &self,
+        table_id: TableId,
+        since: TableVersion,
+    ) -> Result<ItemChanges, Self::Error> {
+        Ok(self.storage.changes_since(table_id, since).await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn table_version(&self, table_id: TableId) -> Result<TableVersion, Self::Error> {
+        Ok(self.storage.table_version(table_id).await?)
+    }
+
     #[instrument(skip(self))]
     async fn get_item(
         &self,
@@ -114,4 +373,145 @@ impl<S: Storage + Send + Sync> RestaurantService for DefaultRestaurantService<S>
     ) -> Result<Option<ItemInfo>, Self::Error> {
         Ok(self.storage.get_item(table_id, item_id).await?)
     }
+
+    #[instrument(skip(self))]
+    async fn get_versioned_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+    ) -> Result<(Option<ItemInfo>, TableVersion), Self::Error> {
+        Ok(self.storage.get_versioned_item(table_id, item_id).await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn advance_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+        to: ItemStatus,
+    ) -> Result<ItemInfo, Self::Error> {
+        match self
+            .storage
+            .advance_item(table_id, item_id.clone(), to, Utc::now())
+            .await?
+        {
+            AdvancedItem::Advanced(item) => Ok(item),
+            AdvancedItem::NotFound => Err(DefaultRestaurantServiceError::ItemNotFound(item_id)),
+            AdvancedItem::IllegalTransition { from } => {
+                Err(DefaultRestaurantServiceError::IllegalTransition { from, to })
+            }
+        }
+    }
+
+    #[instrument(skip(self, items))]
+    async fn add_menu_items(
+        &self,
+        items: impl Iterator<Item = NewMenuItem> + Send,
+    ) -> Result<Vec<MenuItem>, Self::Error> {
+        Ok(self.storage.add_menu_items(items).await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn list_menu(&self) -> Result<Vec<MenuItem>, Self::Error> {
+        Ok(self.storage.list_menu().await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn set_menu_item_available(
+        &self,
+        menu_item_id: MenuItemId,
+        available: bool,
+    ) -> Result<MenuItem, Self::Error> {
+        self.storage
+            .set_menu_item_available(menu_item_id.clone(), available)
+            .await?
+            .ok_or(DefaultRestaurantServiceError::MenuItemNotFound(
+                menu_item_id,
+            ))
+    }
+
+    #[instrument(skip(self, tables))]
+    async fn add_tables(
+        &self,
+        tables: impl Iterator<Item = NewTable> + Send,
+    ) -> Result<Vec<Table>, Self::Error> {
+        Ok(self.storage.add_tables(tables).await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn list_tables(&self) -> Result<Vec<Table>, Self::Error> {
+        Ok(self.storage.list_tables().await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn get_table(&self, table_id: TableId) -> Result<Option<Table>, Self::Error> {
+        Ok(self.storage.get_table(table_id).await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn set_table_active(
+        &self,
+        table_id: TableId,
+        active: bool,
+    ) -> Result<Table, Self::Error> {
+        self.storage
+            .set_table_active(table_id.clone(), active)
+            .await?
+            .ok_or(DefaultRestaurantServiceError::TableNotFound(table_id))
+    }
+
+    #[instrument(skip(self))]
+    async fn wait_for_changes(
+        &self,
+        table_id: TableId,
+        after: TableVersion,
+        timeout: std::time::Duration,
+    ) -> Result<Option<ItemChanges>, Self::Error> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        // Subscribe first, so change made while version is checked is not missed
+        let mut events = self.subscribe(Some(table_id.clone())).await?;
+        loop {
+            if self.storage.table_version(table_id.clone()).await? != after {
+                let changes = self
+                    .storage
+                    .changes_since(table_id, TableVersion::default())
+                    .await?;
+                return Ok(Some(changes));
+            }
+            tokio::select! {
+                event = events.next() => {
+                    // Lagged subscriber, table has surely changed, but subscription is over
+                    if event.is_none() {
+                        events = self.subscribe(Some(table_id.clone())).await?;
+                    }
+                }
+                _ = tokio::time::sleep(WAIT_RECHECK_INTERVAL) => {}
+                _ = tokio::time::sleep_until(deadline) => return Ok(None),
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn subscribe(&self, table_id: Option<TableId>) -> Result<ItemEvents, Self::Error> {
+        // Subscribe first, so nothing is missed while table is checked
+        let receiver = self.storage.subscribe();
+        if let Some(table_id) = &table_id {
+            if self.get_table(table_id.clone()).await?.is_none() {
+                return Err(DefaultRestaurantServiceError::TableNotFound(
+                    table_id.clone(),
+                ));
+            }
+        }
+
+        let events = BroadcastStream::new(receiver)
+            // Lagged subscriber has missed some events, so it can't be trusted anymore
+            .take_while(|event| future::ready(event.is_ok()))
+            .filter_map(move |event| {
+                let event = event
+                    .ok()
+                    .filter(|event| table_id.as_ref().is_none_or(|t| event.table_id() == t));
+                future::ready(event)
+            });
+        Ok(Box::pin(events))
+    }
 }
@@ -1,9 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
 use std::ops::RangeFrom;
 
 use async_trait::async_trait;
-use tokio::sync::Mutex;
+use chrono::{DateTime, Duration, Utc};
+use tokio::sync::{broadcast, Mutex};
 use tracing::instrument;
 
 use super::model::*;
@@ -11,6 +12,22 @@ use super::model::*;
 struct SimpleMemoryStorageInner {
     item_id_seq: RangeFrom<i32>,
     items: HashMap<TableId, Vec<ItemInfo>>,
+    /// Bumped on every change of items of table, see `Storage::changes_since`
+    table_versions: HashMap<TableId, TableVersion>,
+    /// Version of table at last change of each item
+    item_versions: HashMap<ItemId, TableVersion>,
+    /// Removed items with version of table and time of removal, in order of removal
+    removed_items: HashMap<TableId, Vec<(TableVersion, DateTime<Utc>, ItemId)>>,
+    /// Latest version of removed items dropped after `tombstone_retention`
+    pruned_versions: HashMap<TableId, TableVersion>,
+    /// When key was used, and ids of items created with it
+    idempotency_keys: HashMap<(TableId, IdempotencyKey), (DateTime<Utc>, Vec<ItemId>)>,
+    menu_item_id_seq: RangeFrom<i32>,
+    /// Ordered by menu item id, as ids are generated in increasing order
+    menu: Vec<MenuItem>,
+    table_id_seq: RangeFrom<i32>,
+    /// Ordered by table id, same as menu
+    tables: Vec<Table>,
 }
 
 impl Default for SimpleMemoryStorageInner {
@@ -18,12 +35,41 @@ impl Default for SimpleMemoryStorageInner {
         SimpleMemoryStorageInner {
             item_id_seq: 0..,
             items: Default::default(),
+            table_versions: Default::default(),
+            item_versions: Default::default(),
+            removed_items: Default::default(),
+            pruned_versions: Default::default(),
+            idempotency_keys: Default::default(),
+            menu_item_id_seq: 0..,
+            menu: Default::default(),
+            table_id_seq: 0..,
+            tables: Default::default(),
         }
     }
 }
 
 impl SimpleMemoryStorageInner {
-    fn add_items(&mut self, table_id: TableId, items: impl Iterator<Item = NewItem> + Send) {
+    fn table_version(&self, table_id: &TableId) -> TableVersion {
+        self.table_versions
+            .get(table_id)
+            .copied()
+            .unwrap_or(TableVersion(0))
+    }
+
+    fn bump_version(&mut self, table_id: &TableId) -> TableVersion {
+        let version = self
+            .table_versions
+            .entry(table_id.clone())
+            .or_insert(TableVersion(0));
+        version.0 += 1;
+        *version
+    }
+
+    fn add_items(
+        &mut self,
+        table_id: TableId,
+        items: impl Iterator<Item = NewItem> + Send,
+    ) -> Vec<ItemInfo> {
         let mut generate_item_id = || -> ItemId {
             self.item_id_seq
                 .next()
@@ -31,23 +77,88 @@ impl SimpleMemoryStorageInner {
                 .into()
         };
 
-        self.items
-            .entry(table_id.clone())
-            .or_insert(vec![])
-            .extend(items.map(|i| ItemInfo {
+        let new_items = items
+            .map(|i| ItemInfo {
                 table_id: table_id.clone(),
                 item_id: generate_item_id(),
+                menu_item_id: Some(i.menu_item_id),
                 name: i.name,
                 comment: i.comment,
                 created_at: i.created_at,
                 forecast_ready_at: i.forecast_ready_at,
-            }));
+                status: ItemStatus::Ordered,
+                cooking_at: None,
+                ready_at: None,
+                served_at: None,
+            })
+            .collect::<Vec<_>>();
+
+        if !new_items.is_empty() {
+            let version = self.bump_version(&table_id);
+            for item in &new_items {
+                self.item_versions.insert(item.item_id.clone(), version);
+            }
+        }
+        self.items
+            .entry(table_id)
+            .or_insert(vec![])
+            .extend(new_items.iter().cloned());
+
+        new_items
+    }
+
+    /// Expired tombstones of table are dropped here, as they only pile up when items are removed
+    fn add_tombstones(&mut self, table_id: &TableId, version: TableVersion, removed: &[ItemId]) {
+        let now = Utc::now();
+        let expired_before = now - tombstone_retention();
+        let tombstones = self.removed_items.entry(table_id.clone()).or_default();
+        let expired = tombstones.partition_point(|(_, removed_at, _)| *removed_at < expired_before);
+        if let Some((pruned, _, _)) = tombstones.drain(..expired).next_back() {
+            self.pruned_versions.insert(table_id.clone(), pruned);
+        }
+        tombstones.extend(
+            removed
+                .iter()
+                .map(|item_id| (version, now, item_id.clone())),
+        );
+    }
+
+    fn get_items(&self, table_id: &TableId, item_ids: &[ItemId]) -> Vec<ItemInfo> {
+        self.items
+            .get(table_id)
+            .map(|items| {
+                items
+                    .iter()
+                    .filter(|item| item_ids.contains(&item.item_id))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or(vec![])
     }
 }
 
-#[derive(Default)]
+/// Events are published while data is locked, so they go in same order as changes
 pub struct SimpleMemoryStorage {
     inner: Mutex<SimpleMemoryStorageInner>,
+    events: broadcast::Sender<ItemEvent>,
+}
+
+impl Default for SimpleMemoryStorage {
+    fn default() -> Self {
+        SimpleMemoryStorage {
+            inner: Default::default(),
+            events: broadcast::channel(ITEM_EVENTS_CAPACITY).0,
+        }
+    }
+}
+
+impl SimpleMemoryStorage {
+    fn publish(&self, events: impl IntoIterator<Item = ItemEvent>) {
+        for event in events {
+            // Fails only when nobody is subscribed
+            let _ = self.events.send(event);
+        }
+    }
 }
 
 type SimpleMemoryStorageError = Infallible;
@@ -60,11 +171,49 @@ impl Storage for SimpleMemoryStorage {
     async fn add_items(
         &self,
         table_id: TableId,
+        idempotency_key: Option<IdempotencyKey>,
         items: impl Iterator<Item = NewItem> + Send,
-    ) -> Result<(), Self::Error> {
+    ) -> Result<Vec<ItemInfo>, Self::Error> {
         let mut data = self.inner.lock().await;
-        data.add_items(table_id, items);
-        Ok(())
+
+        let Some(idempotency_key) = idempotency_key else {
+            let new_items = data.add_items(table_id, items);
+            self.publish(new_items.iter().cloned().map(ItemEvent::Added));
+            return Ok(new_items);
+        };
+
+        let now = Utc::now();
+        let expired_before = now - idempotency_key_retention();
+        data.idempotency_keys
+            .retain(|_, (used_at, _)| *used_at >= expired_before);
+
+        let key = (table_id.clone(), idempotency_key);
+        if let Some((_, item_ids)) = data.idempotency_keys.get(&key) {
+            return Ok(data.get_items(&table_id, item_ids));
+        }
+
+        let new_items = data.add_items(table_id, items);
+        data.idempotency_keys.insert(
+            key,
+            (now, new_items.iter().map(|i| i.item_id.clone()).collect()),
+        );
+        self.publish(new_items.iter().cloned().map(ItemEvent::Added));
+        Ok(new_items)
+    }
+
+    #[instrument(skip(self))]
+    async fn get_idempotent_items(
+        &self,
+        table_id: TableId,
+        idempotency_key: IdempotencyKey,
+    ) -> Result<Option<Vec<ItemInfo>>, Self::Error> {
+        let data = self.inner.lock().await;
+        let expired_before = Utc::now() - idempotency_key_retention();
+        Ok(data
+            .idempotency_keys
+            .get(&(table_id.clone(), idempotency_key))
+            .filter(|(used_at, _)| *used_at >= expired_before)
+            .map(|(_, item_ids)| data.get_items(&table_id, item_ids)))
     }
 
     #[instrument(skip(self, item_ids))]
@@ -72,19 +221,41 @@ impl Storage for SimpleMemoryStorage {
         &self,
         table_id: TableId,
         item_ids: impl Iterator<Item = ItemId> + Send,
-    ) -> Result<(), Self::Error> {
+    ) -> Result<RemovedItems, Self::Error> {
         let mut data = self.inner.lock().await;
 
         // TODO collect to Set?
         // TODO Use one more map in data and remove .collect() at all?
         let item_ids = item_ids.collect::<Vec<_>>();
+        let mut removed = vec![];
 
         // We can leave table entry in map, assuming there's a cap on total tables in storage
         if let Some(table_items) = data.items.get_mut(&table_id) {
-            table_items.retain(|i| !item_ids.contains(&i.item_id));
+            table_items.retain(|i| {
+                let remove = item_ids.contains(&i.item_id);
+                if remove {
+                    removed.push(i.item_id.clone());
+                }
+                !remove
+            });
         }
 
-        Ok(())
+        if !removed.is_empty() {
+            let version = data.bump_version(&table_id);
+            for item_id in &removed {
+                data.item_versions.remove(item_id);
+            }
+            data.add_tombstones(&table_id, version, &removed);
+        }
+
+        self.publish(removed.iter().map(|item_id| ItemEvent::Removed {
+            table_id: table_id.clone(),
+            item_id: item_id.clone(),
+        }));
+
+        Ok(RemovedItems::from_requested(item_ids, |id| {
+            removed.contains(id)
+        }))
     }
 
     #[instrument(skip(self))]
@@ -94,19 +265,60 @@ impl Storage for SimpleMemoryStorage {
         Ok(data
             .items
             .get_mut(&table_id)
-            .map(|items| {
-                items
-                    .iter()
-                    .map(|item| ItemInfoShort {
-                        table_id: item.table_id.clone(),
-                        item_id: item.item_id.clone(),
-                        name: item.name.clone(),
-                    })
-                    .collect()
-            })
+            .map(|items| items.iter().map(ItemInfo::to_short).collect())
             .unwrap_or(vec![]))
     }
 
+    #[instrument(skip(self))]
+    async fn changes_since(
+        &self,
+        table_id: TableId,
+        since: TableVersion,
+    ) -> Result<ItemChanges, Self::Error> {
+        let data = self.inner.lock().await;
+
+        let version = data.table_version(&table_id);
+        let items = data.items.get(&table_id).map(Vec::as_slice).unwrap_or(&[]);
+        let pruned_version = data
+            .pruned_versions
+            .get(&table_id)
+            .copied()
+            .unwrap_or(TableVersion(0));
+        if since <= TableVersion(0) || since > version || since < pruned_version {
+            return Ok(ItemChanges {
+                version,
+                reset: true,
+                changed: items.iter().map(ItemInfo::to_short).collect(),
+                removed: vec![],
+            });
+        }
+
+        Ok(ItemChanges {
+            version,
+            reset: false,
+            changed: items
+                .iter()
+                .filter(|item| data.item_versions[&item.item_id] > since)
+                .map(ItemInfo::to_short)
+                .collect(),
+            removed: data
+                .removed_items
+                .get(&table_id)
+                .into_iter()
+                .flatten()
+                .filter(|(version, _, _)| *version > since)
+                .map(|(_, _, item_id)| item_id.clone())
+                .collect(),
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn table_version(&self, table_id: TableId) -> Result<TableVersion, Self::Error> {
+        let data = self.inner.lock().await;
+
+        Ok(data.table_version(&table_id))
+    }
+
     #[instrument(skip(self))]
     async fn get_item(
         &self,
@@ -121,6 +333,275 @@ impl Storage for SimpleMemoryStorage {
             .map(|items| items.iter().find(|item| item.item_id == item_id).cloned())
             .unwrap_or(None))
     }
+
+    #[instrument(skip(self))]
+    async fn get_versioned_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+    ) -> Result<(Option<ItemInfo>, TableVersion), Self::Error> {
+        let data = self.inner.lock().await;
+
+        let item = data.get_items(&table_id, &[item_id]).into_iter().next();
+        Ok((item, data.table_version(&table_id)))
+    }
+
+    #[instrument(skip(self))]
+    async fn advance_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+        to: ItemStatus,
+        at: DateTime<Utc>,
+    ) -> Result<AdvancedItem, Self::Error> {
+        let mut data = self.inner.lock().await;
+
+        let item = data
+            .items
+            .get_mut(&table_id)
+            .and_then(|items| items.iter_mut().find(|item| item.item_id == item_id));
+        let Some(item) = item else {
+            return Ok(AdvancedItem::NotFound);
+        };
+
+        if let Err(from) = item.advance(to, at) {
+            return Ok(AdvancedItem::IllegalTransition { from });
+        }
+        let item = item.clone();
+        let version = data.bump_version(&table_id);
+        data.item_versions.insert(item_id, version);
+        self.publish([ItemEvent::Advanced(item.clone())]);
+        Ok(AdvancedItem::Advanced(item))
+    }
+
+    #[instrument(skip(self, items))]
+    async fn add_menu_items(
+        &self,
+        items: impl Iterator<Item = NewMenuItem> + Send,
+    ) -> Result<Vec<MenuItem>, Self::Error> {
+        let mut data = self.inner.lock().await;
+
+        let mut new_items = vec![];
+        for i in items {
+            let menu_item_id = data
+                .menu_item_id_seq
+                .next()
+                .expect("Menu item ids sequence overflow")
+                .into();
+            new_items.push(MenuItem {
+                menu_item_id,
+                name: i.name,
+                category: i.category,
+                available: i.available,
+            });
+        }
+        data.menu.extend(new_items.iter().cloned());
+
+        Ok(new_items)
+    }
+
+    #[instrument(skip(self))]
+    async fn list_menu(&self) -> Result<Vec<MenuItem>, Self::Error> {
+        let data = self.inner.lock().await;
+
+        Ok(data.menu.clone())
+    }
+
+    #[instrument(skip(self, menu_item_ids))]
+    async fn get_menu_items(
+        &self,
+        menu_item_ids: impl Iterator<Item = MenuItemId> + Send,
+    ) -> Result<Vec<MenuItem>, Self::Error> {
+        let data = self.inner.lock().await;
+
+        let menu_item_ids = menu_item_ids.collect::<Vec<_>>();
+        Ok(data
+            .menu
+            .iter()
+            .filter(|item| menu_item_ids.contains(&item.menu_item_id))
+            .cloned()
+            .collect())
+    }
+
+    #[instrument(skip(self))]
+    async fn set_menu_item_available(
+        &self,
+        menu_item_id: MenuItemId,
+        available: bool,
+    ) -> Result<Option<MenuItem>, Self::Error> {
+        let mut data = self.inner.lock().await;
+
+        Ok(data
+            .menu
+            .iter_mut()
+            .find(|item| item.menu_item_id == menu_item_id)
+            .map(|item| {
+                item.available = available;
+                item.clone()
+            }))
+    }
+
+    #[instrument(skip(self))]
+    async fn kitchen_stats(&self, since: DateTime<Utc>) -> Result<KitchenStats, Self::Error> {
+        let data = self.inner.lock().await;
+
+        let mut stats = KitchenStats::default();
+        // Total cooking time and count of items that got ready
+        let mut prep_times: BTreeMap<i32, (Duration, i64)> = BTreeMap::new();
+        for item in data.items.values().flatten() {
+            if item.created_at < since {
+                continue;
+            }
+            match (
+                item.status,
+                &item.menu_item_id,
+                item.cooking_at,
+                item.ready_at,
+            ) {
+                _ if item.is_outstanding() => stats.queued += 1,
+                (_, Some(menu_item_id), Some(cooking_at), Some(ready_at)) => {
+                    let (total, completed) = prep_times
+                        .entry(menu_item_id.0)
+                        .or_insert((Duration::zero(), 0));
+                    *total = *total + (ready_at - cooking_at);
+                    *completed += 1;
+                }
+                _ => {}
+            }
+        }
+        stats.prep_times = prep_times
+            .into_iter()
+            .map(|(menu_item_id, (total, completed))| PrepTime {
+                menu_item_id: menu_item_id.into(),
+                completed,
+                average: total / completed as i32,
+            })
+            .collect();
+
+        Ok(stats)
+    }
+
+    #[instrument(skip(self))]
+    async fn outstanding_items(&self, since: DateTime<Utc>) -> Result<Vec<ItemInfo>, Self::Error> {
+        let data = self.inner.lock().await;
+
+        let mut items = data
+            .items
+            .values()
+            .flatten()
+            .filter(|item| item.created_at >= since && item.is_outstanding())
+            .cloned()
+            .collect::<Vec<_>>();
+        items.sort_by_key(|item| item.item_id.0);
+
+        Ok(items)
+    }
+
+    #[instrument(skip(self, forecasts))]
+    async fn update_forecasts(
+        &self,
+        forecasts: impl Iterator<Item = ForecastUpdate> + Send,
+    ) -> Result<u64, Self::Error> {
+        let mut data = self.inner.lock().await;
+
+        let mut updated = vec![];
+        for forecast in forecasts {
+            let item = data.items.get_mut(&forecast.table_id).and_then(|items| {
+                items
+                    .iter_mut()
+                    .find(|item| item.item_id == forecast.item_id)
+            });
+            if let Some(item) = item.filter(|item| item.is_outstanding()) {
+                item.forecast_ready_at = forecast.forecast_ready_at;
+                updated.push((forecast, item.clone()));
+            }
+        }
+
+        // Single bump per table, same as with any other change of multiple items
+        let mut versions = HashMap::new();
+        for (forecast, _) in &updated {
+            let version = match versions.get(&forecast.table_id) {
+                Some(version) => *version,
+                None => {
+                    let version = data.bump_version(&forecast.table_id);
+                    versions.insert(forecast.table_id.clone(), version);
+                    version
+                }
+            };
+            data.item_versions.insert(forecast.item_id.clone(), version);
+        }
+
+        let count = updated.len() as u64;
+        self.publish(
+            updated
+                .into_iter()
+                .map(|(_, item)| ItemEvent::Forecast(item)),
+        );
+        Ok(count)
+    }
+
+    #[instrument(skip(self, tables))]
+    async fn add_tables(
+        &self,
+        tables: impl Iterator<Item = NewTable> + Send,
+    ) -> Result<Vec<Table>, Self::Error> {
+        let mut data = self.inner.lock().await;
+
+        let mut new_tables = vec![];
+        for t in tables {
+            let table_id = data
+                .table_id_seq
+                .next()
+                .expect("Table ids sequence overflow")
+                .into();
+            new_tables.push(Table {
+                table_id,
+                name: t.name,
+                seats: t.seats,
+                section: t.section,
+                active: true,
+            });
+        }
+        data.tables.extend(new_tables.iter().cloned());
+
+        Ok(new_tables)
+    }
+
+    #[instrument(skip(self))]
+    async fn list_tables(&self) -> Result<Vec<Table>, Self::Error> {
+        let data = self.inner.lock().await;
+
+        Ok(data.tables.clone())
+    }
+
+    #[instrument(skip(self))]
+    async fn get_table(&self, table_id: TableId) -> Result<Option<Table>, Self::Error> {
+        let data = self.inner.lock().await;
+
+        Ok(data.tables.iter().find(|t| t.table_id == table_id).cloned())
+    }
+
+    #[instrument(skip(self))]
+    async fn set_table_active(
+        &self,
+        table_id: TableId,
+        active: bool,
+    ) -> Result<Option<Table>, Self::Error> {
+        let mut data = self.inner.lock().await;
+
+        Ok(data
+            .tables
+            .iter_mut()
+            .find(|t| t.table_id == table_id)
+            .map(|t| {
+                t.active = active;
+                t.clone()
+            }))
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ItemEvent> {
+        self.events.subscribe()
+    }
 }
 
 #[cfg(test)]
@@ -131,6 +612,50 @@ mod tests {
 
     #[test]
     fn test_memory_storage() {
-        test_suite(&|| async { SimpleMemoryStorage::default() }).unwrap()
+        test_suite(|| async { SimpleMemoryStorage::default() }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_expired_tombstones() {
+        let storage = SimpleMemoryStorage::default();
+        let table_id = TableId::from(1);
+        let new_item = || NewItem {
+            menu_item_id: 1.into(),
+            name: "item".into(),
+            comment: "".into(),
+            created_at: Utc::now(),
+            forecast_ready_at: Utc::now(),
+        };
+        let added = storage
+            .add_items(table_id.clone(), None, [new_item(), new_item()].into_iter())
+            .await
+            .unwrap();
+        let before = storage.table_version(table_id.clone()).await.unwrap();
+        storage
+            .remove_items(table_id.clone(), [added[0].item_id.clone()].into_iter())
+            .await
+            .unwrap();
+        let after_first = storage.table_version(table_id.clone()).await.unwrap();
+
+        for tombstones in storage.inner.lock().await.removed_items.values_mut() {
+            for (_, removed_at, _) in tombstones {
+                *removed_at -= tombstone_retention();
+            }
+        }
+        storage
+            .remove_items(table_id.clone(), [added[1].item_id.clone()].into_iter())
+            .await
+            .unwrap();
+
+        // First removal is forgotten, so cursor before it can't get complete changes
+        let changes = storage
+            .changes_since(table_id.clone(), before)
+            .await
+            .unwrap();
+        assert!(changes.reset);
+        assert!(changes.changed.is_empty());
+        let changes = storage.changes_since(table_id, after_first).await.unwrap();
+        assert!(!changes.reset);
+        assert_eq!(changes.removed, vec![added[1].item_id.clone()]);
     }
 }
@@ -1,38 +1,342 @@
+use std::fmt;
+use std::sync::Arc;
+
 use async_trait::async_trait;
-use chrono::{DateTime, Utc};
-use derive_more::From;
+use chrono::{DateTime, Duration, Utc};
+use derive_more::{Display, From};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone, From)]
+#[derive(Debug, Display, Eq, PartialEq, Hash, Clone, From, Serialize, Deserialize)]
 pub struct TableId(pub(super) i32);
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone, From)]
+#[derive(Debug, Display, Eq, PartialEq, Hash, Clone, From, Serialize, Deserialize)]
 pub struct ItemId(pub(super) i32);
 
+#[derive(Debug, Display, Eq, PartialEq, Hash, Clone, From, Serialize, Deserialize)]
+pub struct MenuItemId(pub(super) i32);
+
+/// Counter of changes of items on a table, it is 0 until first change
+/// Only comparable within same table of same storage
+#[derive(
+    Debug,
+    Default,
+    Display,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Clone,
+    Copy,
+    From,
+    Serialize,
+    Deserialize,
+)]
+pub struct TableVersion(pub(super) i64);
+
+/// Client-supplied key to make `add_items` safe to retry, unique within a table
+#[derive(Debug, Display, Eq, PartialEq, Hash, Clone, From, Serialize, Deserialize)]
+pub struct IdempotencyKey(pub(super) String);
+
+impl IdempotencyKey {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// How long storage should remember idempotency keys
+/// Retries are expected to come within seconds, so this is very generous
+pub fn idempotency_key_retention() -> Duration {
+    Duration::hours(24)
+}
+
+/// How long storage should remember removed items for `changes_since`
+/// Tablets resume within minutes after dropped connection, ones offline for longer list items again
+pub fn tombstone_retention() -> Duration {
+    Duration::hours(24)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewTable {
+    /// Shown to staff, e.g. "T12" or "Window 3"
+    pub name: String,
+    pub seats: i32,
+    /// Part of restaurant, e.g. "terrace", only used to group tables for presentation
+    pub section: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Table {
+    pub table_id: TableId,
+    pub name: String,
+    pub seats: i32,
+    pub section: String,
+    /// Inactive tables can not take new orders, e.g. when they were moved out for renovation
+    pub active: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewMenuItem {
+    pub name: String,
+    /// Free text, only used to group items for presentation, e.g. "drinks"
+    pub category: String,
+    pub available: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MenuItem {
+    pub menu_item_id: MenuItemId,
+    pub name: String,
+    pub category: String,
+    /// Unavailable items stay on the menu, but can not be ordered, e.g. when kitchen ran out of something
+    pub available: bool,
+}
+
 #[derive(Clone)]
 pub struct NewItem {
+    pub menu_item_id: MenuItemId,
+    /// Copy of menu item name at the time of order
     pub name: String,
     pub comment: String,
     pub created_at: DateTime<Utc>,
     pub forecast_ready_at: DateTime<Utc>,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+/// Lifecycle of an item, each item goes through all of these in order
+/// JSON, PostgreSQL and `Display` all use names from `as_str`
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(into = "&'static str", try_from = "String")]
+pub enum ItemStatus {
+    Ordered,
+    Cooking,
+    Ready,
+    Served,
+}
+
+impl ItemStatus {
+    pub const ALL: [ItemStatus; 4] = [
+        ItemStatus::Ordered,
+        ItemStatus::Cooking,
+        ItemStatus::Ready,
+        ItemStatus::Served,
+    ];
+
+    /// The only status item can be advanced to from this one
+    pub fn next(self) -> Option<ItemStatus> {
+        match self {
+            ItemStatus::Ordered => Some(ItemStatus::Cooking),
+            ItemStatus::Cooking => Some(ItemStatus::Ready),
+            ItemStatus::Ready => Some(ItemStatus::Served),
+            ItemStatus::Served => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ItemStatus::Ordered => "ordered",
+            ItemStatus::Cooking => "cooking",
+            ItemStatus::Ready => "ready",
+            ItemStatus::Served => "served",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<ItemStatus> {
+        ItemStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str() == s)
+    }
+}
+
+impl From<ItemStatus> for &'static str {
+    fn from(status: ItemStatus) -> Self {
+        status.as_str()
+    }
+}
+
+impl TryFrom<String> for ItemStatus {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        ItemStatus::parse(&s).ok_or_else(|| format!("unknown item status `{s}`"))
+    }
+}
+
+impl fmt::Display for ItemStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ItemInfoShort {
     pub table_id: TableId,
     pub item_id: ItemId,
     pub name: String,
+    pub status: ItemStatus,
+    /// Updated while item is not ready, so tablets could show live ETA
+    pub forecast_ready_at: DateTime<Utc>,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ItemInfo {
     pub table_id: TableId,
     pub item_id: ItemId,
+    /// Items ordered before menu was introduced don't have it
+    pub menu_item_id: Option<MenuItemId>,
     pub name: String,
     pub comment: String,
     pub created_at: DateTime<Utc>,
     pub forecast_ready_at: DateTime<Utc>,
+    /// Item is in `Ordered` status since `created_at`, timestamps below are set on reaching each status
+    pub status: ItemStatus,
+    pub cooking_at: Option<DateTime<Utc>>,
+    pub ready_at: Option<DateTime<Utc>>,
+    pub served_at: Option<DateTime<Utc>>,
 }
 
+impl ItemInfo {
+    /// Move item to next status, or return current one if `to` is not next
+    pub fn advance(&mut self, to: ItemStatus, at: DateTime<Utc>) -> Result<(), ItemStatus> {
+        if self.status.next() != Some(to) {
+            return Err(self.status);
+        }
+        match to {
+            // Unreachable, as there's no status before Ordered
+            ItemStatus::Ordered => return Err(self.status),
+            ItemStatus::Cooking => self.cooking_at = Some(at),
+            ItemStatus::Ready => self.ready_at = Some(at),
+            ItemStatus::Served => self.served_at = Some(at),
+        }
+        self.status = to;
+        Ok(())
+    }
+
+    /// Item is waiting in kitchen queue, or is being cooked
+    pub fn is_outstanding(&self) -> bool {
+        matches!(self.status, ItemStatus::Ordered | ItemStatus::Cooking)
+    }
+
+    pub fn to_short(&self) -> ItemInfoShort {
+        ItemInfoShort {
+            table_id: self.table_id.clone(),
+            item_id: self.item_id.clone(),
+            name: self.name.clone(),
+            status: self.status,
+            forecast_ready_at: self.forecast_ready_at,
+        }
+    }
+}
+
+/// Outcome of `advance_item`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AdvancedItem {
+    Advanced(ItemInfo),
+    NotFound,
+    /// Item is in status from which requested one is not reachable
+    IllegalTransition {
+        from: ItemStatus,
+    },
+}
+
+/// Cooking time of one menu item, from `cooking_at` to `ready_at`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PrepTime {
+    pub menu_item_id: MenuItemId,
+    /// Count of items that got ready
+    pub completed: i64,
+    pub average: Duration,
+}
+
+/// Aggregated state of kitchen, it is what forecasts are based on
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct KitchenStats {
+    /// Only menu items with at least one item that got ready, ordered by menu item id
+    pub prep_times: Vec<PrepTime>,
+    /// Items that are ordered or cooking right now
+    pub queued: i64,
+}
+
+/// New forecast for item that is not ready yet
+#[derive(Clone, Debug)]
+pub struct ForecastUpdate {
+    pub table_id: TableId,
+    pub item_id: ItemId,
+    pub forecast_ready_at: DateTime<Utc>,
+}
+
+/// Outcome of `remove_items`, both lists follow order of requested ids, without duplicates
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RemovedItems {
+    pub removed: Vec<ItemId>,
+    /// Ids that were not present on table, e.g. already removed by someone else
+    pub not_found: Vec<ItemId>,
+}
+
+impl RemovedItems {
+    pub(super) fn from_requested(
+        requested: impl IntoIterator<Item = ItemId>,
+        was_removed: impl Fn(&ItemId) -> bool,
+    ) -> RemovedItems {
+        let mut result = RemovedItems::default();
+        for item_id in requested {
+            if result.removed.contains(&item_id) || result.not_found.contains(&item_id) {
+                continue;
+            }
+            if was_removed(&item_id) {
+                result.removed.push(item_id);
+            } else {
+                result.not_found.push(item_id);
+            }
+        }
+        result
+    }
+}
+
+/// Outcome of `changes_since`
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ItemChanges {
+    /// Version changes are up to, should be passed as cursor next time
+    pub version: TableVersion,
+    /// Client should drop whatever it has, `changed` lists all items of table then, and `removed` is empty
+    /// Happens for cursor of 0, for cursor ahead of table version, e.g. one from other database,
+    /// and for cursor older than removed items storage still remembers, see `tombstone_retention`
+    pub reset: bool,
+    /// Items added or changed since cursor, in same order as in `list_items`
+    pub changed: Vec<ItemInfoShort>,
+    /// Items removed since cursor, could include ones added after it
+    pub removed: Vec<ItemId>,
+}
+
+/// Change of items on a table, as seen by subscribers
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ItemEvent {
+    Added(ItemInfo),
+    Removed {
+        table_id: TableId,
+        item_id: ItemId,
+    },
+    /// Item went to next status, carries updated item
+    Advanced(ItemInfo),
+    /// Forecast of outstanding item was recalculated, carries updated item
+    Forecast(ItemInfo),
+}
+
+impl ItemEvent {
+    pub fn table_id(&self) -> &TableId {
+        match self {
+            ItemEvent::Added(item) | ItemEvent::Advanced(item) | ItemEvent::Forecast(item) => {
+                &item.table_id
+            }
+            ItemEvent::Removed { table_id, .. } => table_id,
+        }
+    }
+}
+
+/// How many events subscriber may fall behind before it starts missing them
+pub const ITEM_EVENTS_CAPACITY: usize = 1024;
+
 /// Everything that is needed to persist data
 /// Each method represents atomic operation from the storage PoV
 /// Implementation should guarantee data safety on cancellation: dropped futures can leave
@@ -42,21 +346,34 @@ pub struct ItemInfo {
 pub trait Storage {
     type Error: std::error::Error;
 
-    /// Adds new items to table. Table id is not validated.
+    /// Adds new items to table. Table id and menu item ids are not validated.
     /// Should generate unique item id for each new item.
+    /// Returns created items in same order as they were passed.
+    /// When idempotency key was already used for this table within `idempotency_key_retention`,
+    /// should not add anything, and return items created by that first call instead, skipping
+    /// ones removed since then. Items passed with replayed call are not compared with original ones.
     async fn add_items(
         &self,
         table_id: TableId,
+        idempotency_key: Option<IdempotencyKey>,
         items: impl Iterator<Item = NewItem> + Send,
-    ) -> Result<(), Self::Error>;
+    ) -> Result<Vec<ItemInfo>, Self::Error>;
+
+    /// Items that `add_items` would replay for this key, or None if key was not used for this table
+    /// within `idempotency_key_retention`
+    async fn get_idempotent_items(
+        &self,
+        table_id: TableId,
+        idempotency_key: IdempotencyKey,
+    ) -> Result<Option<Vec<ItemInfo>>, Self::Error>;
 
     /// Removes items from table. Table id is not validated.
-    /// Should skip over item ids not present on table.
+    /// Should skip over item ids not present on table, and report them as not found.
     async fn remove_items(
         &self,
         table_id: TableId,
         item_ids: impl Iterator<Item = ItemId> + Send,
-    ) -> Result<(), Self::Error>;
+    ) -> Result<RemovedItems, Self::Error>;
 
     /// List all items for a table.
     /// Should preserve order of elements:
@@ -65,6 +382,19 @@ pub trait Storage {
     /// other started then order of items should be same as order of add_items calls
     async fn list_items(&self, table_id: TableId) -> Result<Vec<ItemInfoShort>, Self::Error>;
 
+    /// Changes of items on table since it was at given version, so client could keep copy of
+    /// `list_items` up to date without fetching it whole.
+    /// Every change of items bumps version of their table: adding, removing, advancing items, and
+    /// updating their forecasts. Table id is not validated.
+    async fn changes_since(
+        &self,
+        table_id: TableId,
+        since: TableVersion,
+    ) -> Result<ItemChanges, Self::Error>;
+
+    /// Current version of table, same as `changes_since` would return, but without reading items
+    async fn table_version(&self, table_id: TableId) -> Result<TableVersion, Self::Error>;
+
     /// Get single item
     /// TableId is not really necessary here, but by having it we can allow for storage
     /// to include TableId to item primary key
@@ -73,4 +403,232 @@ pub trait Storage {
         table_id: TableId,
         item_id: ItemId,
     ) -> Result<Option<ItemInfo>, Self::Error>;
+
+    /// Same as `get_item`, along with version of table, both read at once
+    async fn get_versioned_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+    ) -> Result<(Option<ItemInfo>, TableVersion), Self::Error>;
+
+    /// Move item to next status, recording `at` as time of reaching it.
+    /// Transition should be validated with `ItemInfo::advance` atomically with update.
+    async fn advance_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+        to: ItemStatus,
+        at: DateTime<Utc>,
+    ) -> Result<AdvancedItem, Self::Error>;
+
+    /// Adds items to menu, should generate unique menu item id for each one.
+    /// Returns created menu items in same order as they were passed.
+    async fn add_menu_items(
+        &self,
+        items: impl Iterator<Item = NewMenuItem> + Send,
+    ) -> Result<Vec<MenuItem>, Self::Error>;
+
+    /// Whole menu, ordered by menu item id
+    async fn list_menu(&self) -> Result<Vec<MenuItem>, Self::Error>;
+
+    /// Menu items with given ids, ordered by menu item id. Unknown ids are skipped.
+    async fn get_menu_items(
+        &self,
+        menu_item_ids: impl Iterator<Item = MenuItemId> + Send,
+    ) -> Result<Vec<MenuItem>, Self::Error>;
+
+    /// Returns updated menu item, or None if it does not exist
+    async fn set_menu_item_available(
+        &self,
+        menu_item_id: MenuItemId,
+        available: bool,
+    ) -> Result<Option<MenuItem>, Self::Error>;
+
+    /// Prep times and queue of items ordered since given time.
+    /// Older items are not considered: prep times from other shifts are not representative,
+    /// and items that are still not ready are most probably abandoned.
+    /// Items without menu item are only counted in queue.
+    async fn kitchen_stats(&self, since: DateTime<Utc>) -> Result<KitchenStats, Self::Error>;
+
+    /// Items ordered since given time that are not ready yet, for all tables, ordered by item id.
+    /// Same as for `kitchen_stats`, older items are considered abandoned.
+    async fn outstanding_items(&self, since: DateTime<Utc>) -> Result<Vec<ItemInfo>, Self::Error>;
+
+    /// Sets `forecast_ready_at` of items, skipping items that got ready or were removed meanwhile.
+    /// Publishes `ItemEvent::Forecast` for every updated item, and returns count of them.
+    async fn update_forecasts(
+        &self,
+        forecasts: impl Iterator<Item = ForecastUpdate> + Send,
+    ) -> Result<u64, Self::Error>;
+
+    /// Registers new tables, should generate unique table id for each one.
+    /// Returns created tables in same order as they were passed, all of them active.
+    async fn add_tables(
+        &self,
+        tables: impl Iterator<Item = NewTable> + Send,
+    ) -> Result<Vec<Table>, Self::Error>;
+
+    /// All registered tables, including inactive ones, ordered by table id
+    async fn list_tables(&self) -> Result<Vec<Table>, Self::Error>;
+
+    async fn get_table(&self, table_id: TableId) -> Result<Option<Table>, Self::Error>;
+
+    /// Returns updated table, or None if it is not registered
+    async fn set_table_active(
+        &self,
+        table_id: TableId,
+        active: bool,
+    ) -> Result<Option<Table>, Self::Error>;
+
+    /// Changes of items made after this call, in order they were made
+    /// Replays of `add_items` with same idempotency key do not change anything, so there are no
+    /// events for them
+    fn subscribe(&self) -> broadcast::Receiver<ItemEvent>;
+}
+
+/// Allows sharing storage between service and background tasks
+#[async_trait]
+impl<S: Storage + Send + Sync> Storage for Arc<S> {
+    type Error = S::Error;
+
+    async fn add_items(
+        &self,
+        table_id: TableId,
+        idempotency_key: Option<IdempotencyKey>,
+        items: impl Iterator<Item = NewItem> + Send,
+    ) -> Result<Vec<ItemInfo>, Self::Error> {
+        self.as_ref()
+            .add_items(table_id, idempotency_key, items)
+            .await
+    }
+
+    async fn get_idempotent_items(
+        &self,
+        table_id: TableId,
+        idempotency_key: IdempotencyKey,
+    ) -> Result<Option<Vec<ItemInfo>>, Self::Error> {
+        self.as_ref()
+            .get_idempotent_items(table_id, idempotency_key)
+            .await
+    }
+
+    async fn remove_items(
+        &self,
+        table_id: TableId,
+        item_ids: impl Iterator<Item = ItemId> + Send,
+    ) -> Result<RemovedItems, Self::Error> {
+        self.as_ref().remove_items(table_id, item_ids).await
+    }
+
+    async fn list_items(&self, table_id: TableId) -> Result<Vec<ItemInfoShort>, Self::Error> {
+        self.as_ref().list_items(table_id).await
+    }
+
+    async fn changes_since(
+        &self,
+        table_id: TableId,
+        since: TableVersion,
+    ) -> Result<ItemChanges, Self::Error> {
+        self.as_ref().changes_since(table_id, since).await
+    }
+
+    async fn table_version(&self, table_id: TableId) -> Result<TableVersion, Self::Error> {
+        self.as_ref().table_version(table_id).await
+    }
+
+    async fn get_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+    ) -> Result<Option<ItemInfo>, Self::Error> {
+        self.as_ref().get_item(table_id, item_id).await
+    }
+
+    async fn get_versioned_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+    ) -> Result<(Option<ItemInfo>, TableVersion), Self::Error> {
+        self.as_ref().get_versioned_item(table_id, item_id).await
+    }
+
+    async fn advance_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+        to: ItemStatus,
+        at: DateTime<Utc>,
+    ) -> Result<AdvancedItem, Self::Error> {
+        self.as_ref().advance_item(table_id, item_id, to, at).await
+    }
+
+    async fn add_menu_items(
+        &self,
+        items: impl Iterator<Item = NewMenuItem> + Send,
+    ) -> Result<Vec<MenuItem>, Self::Error> {
+        self.as_ref().add_menu_items(items).await
+    }
+
+    async fn list_menu(&self) -> Result<Vec<MenuItem>, Self::Error> {
+        self.as_ref().list_menu().await
+    }
+
+    async fn get_menu_items(
+        &self,
+        menu_item_ids: impl Iterator<Item = MenuItemId> + Send,
+    ) -> Result<Vec<MenuItem>, Self::Error> {
+        self.as_ref().get_menu_items(menu_item_ids).await
+    }
+
+    async fn set_menu_item_available(
+        &self,
+        menu_item_id: MenuItemId,
+        available: bool,
+    ) -> Result<Option<MenuItem>, Self::Error> {
+        self.as_ref()
+            .set_menu_item_available(menu_item_id, available)
+            .await
+    }
+
+    async fn kitchen_stats(&self, since: DateTime<Utc>) -> Result<KitchenStats, Self::Error> {
+        self.as_ref().kitchen_stats(since).await
+    }
+
+    async fn outstanding_items(&self, since: DateTime<Utc>) -> Result<Vec<ItemInfo>, Self::Error> {
+        self.as_ref().outstanding_items(since).await
+    }
+
+    async fn update_forecasts(
+        &self,
+        forecasts: impl Iterator<Item = ForecastUpdate> + Send,
+    ) -> Result<u64, Self::Error> {
+        self.as_ref().update_forecasts(forecasts).await
+    }
+
+    async fn add_tables(
+        &self,
+        tables: impl Iterator<Item = NewTable> + Send,
+    ) -> Result<Vec<Table>, Self::Error> {
+        self.as_ref().add_tables(tables).await
+    }
+
+    async fn list_tables(&self) -> Result<Vec<Table>, Self::Error> {
+        self.as_ref().list_tables().await
+    }
+
+    async fn get_table(&self, table_id: TableId) -> Result<Option<Table>, Self::Error> {
+        self.as_ref().get_table(table_id).await
+    }
+
+    async fn set_table_active(
+        &self,
+        table_id: TableId,
+        active: bool,
+    ) -> Result<Option<Table>, Self::Error> {
+        self.as_ref().set_table_active(table_id, active).await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ItemEvent> {
+        self.as_ref().subscribe()
+    }
 }
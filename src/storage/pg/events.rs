@@ -0,0 +1,421 @@
+//! Propagation of item events between instances sharing same database
+//!
+//! Every transaction that changes items sends `NOTIFY` with ids of changed items, and Postgres
+//! delivers them on commit, in commit order, to all listening connections. Each instance listens on
+//! a dedicated connection and publishes events to its own subscribers, including events for changes
+//! made via this instance.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio_postgres::{AsyncMessage, Client, Error as PgError, NoTls, Notification, Transaction};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use super::queries::{GetItemsOfTables, ItemKeysParams, NotifyItemEvents, NotifyParams};
+use super::query::Query;
+use super::{PostgresStorage, PostgresStorageError};
+use crate::storage::model::{ItemEvent, ItemInfo, ITEM_EVENTS_CAPACITY};
+
+const ITEM_EVENTS_CHANNEL: &str = "item_events";
+
+/// Delay before listening again after connection is lost, doubled after each failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Max notifications published together, with their items loaded in a single transaction
+const NOTIFICATIONS_BATCH_SIZE: usize = 500;
+
+/// Payload of notification, it is limited to 8000 bytes, so items are not sent as is
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(super) enum ItemNotification {
+    Added { table_id: i32, item_id: i32 },
+    Removed { table_id: i32, item_id: i32 },
+    Advanced { table_id: i32, item_id: i32 },
+    Forecast { table_id: i32, item_id: i32 },
+}
+
+impl PostgresStorage {
+    /// Notifications are delivered only if transaction commits
+    pub(super) async fn notify_item_events(
+        txn: &Transaction<'_>,
+        notifications: impl IntoIterator<Item = ItemNotification>,
+    ) -> Result<(), PostgresStorageError> {
+        let payloads = notifications
+            .into_iter()
+            .map(|n| serde_json::to_string(&n).expect("Notification is always serializable"))
+            .collect::<Vec<_>>();
+        if payloads.is_empty() {
+            return Ok(());
+        }
+        NotifyItemEvents::execute(
+            txn,
+            &NotifyParams {
+                channel: ITEM_EVENTS_CHANNEL.into(),
+                payloads,
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Start listening on a dedicated connection, pooled ones can't be used as their notifications
+    /// are dropped. Changes committed after this returns will be seen by listener.
+    /// Subscribers of this storage get no events until returned listener runs.
+    pub async fn listen_item_events(
+        self: Arc<Self>,
+        pg_config: &tokio_postgres::Config,
+    ) -> Result<ItemEventsListener, PostgresStorageError> {
+        let listening = Listening::start(pg_config).await?;
+        Ok(ItemEventsListener {
+            storage: self,
+            pg_config: pg_config.clone(),
+            listening,
+        })
+    }
+
+    /// Streams of current subscribers end, same as when they lag behind, so they list items and
+    /// subscribe again. This is for when events could have been lost
+    fn cut_off_subscribers(&self) {
+        *self.events.lock().unwrap() = broadcast::channel(ITEM_EVENTS_CAPACITY).0;
+    }
+
+    async fn get_items_of_tables_once(
+        &self,
+        params: &ItemKeysParams,
+    ) -> Result<Vec<ItemInfo>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_readonly_transaction(&mut db).await?;
+
+        let items = GetItemsOfTables::query(&txn, params).await?;
+
+        txn.commit().await?;
+
+        Ok(items)
+    }
+
+    /// Items of all notifications are loaded together, so listener keeps up with busy database
+    async fn publish_item_events(
+        &self,
+        notifications: Vec<ItemNotification>,
+    ) -> Result<(), PostgresStorageError> {
+        let mut params = ItemKeysParams {
+            table_ids: vec![],
+            item_ids: vec![],
+        };
+        for notification in &notifications {
+            if let ItemNotification::Added { table_id, item_id }
+            | ItemNotification::Advanced { table_id, item_id }
+            | ItemNotification::Forecast { table_id, item_id } = notification
+            {
+                params.table_ids.push(*table_id);
+                params.item_ids.push(*item_id);
+            }
+        }
+        let items = if params.item_ids.is_empty() {
+            HashMap::new()
+        } else {
+            self.with_retry("get_items_of_tables", || {
+                self.get_items_of_tables_once(&params)
+            })
+            .await?
+            .into_iter()
+            .map(|item| ((item.table_id.0, item.item_id.0), item))
+            .collect()
+        };
+
+        let events = self.events.lock().unwrap();
+        for notification in notifications {
+            // Items are loaded as of now, so they could be ahead of the change, or be removed already.
+            // Then it's skipped, as there's a later event coming for it anyway.
+            let event = match notification {
+                ItemNotification::Added { table_id, item_id } => {
+                    match items.get(&(table_id, item_id)) {
+                        Some(item) => ItemEvent::Added(item.clone()),
+                        None => continue,
+                    }
+                }
+                ItemNotification::Advanced { table_id, item_id } => {
+                    match items.get(&(table_id, item_id)) {
+                        Some(item) => ItemEvent::Advanced(item.clone()),
+                        None => continue,
+                    }
+                }
+                ItemNotification::Forecast { table_id, item_id } => {
+                    match items.get(&(table_id, item_id)) {
+                        Some(item) => ItemEvent::Forecast(item.clone()),
+                        None => continue,
+                    }
+                }
+                ItemNotification::Removed { table_id, item_id } => ItemEvent::Removed {
+                    table_id: table_id.into(),
+                    item_id: item_id.into(),
+                },
+            };
+            // Fails only when nobody is subscribed
+            let _ = events.send(event);
+        }
+        Ok(())
+    }
+}
+
+/// Dedicated connection with `LISTEN` on it
+struct Listening {
+    /// Connection is closed when client is dropped
+    _client: Client,
+    notifications: mpsc::UnboundedReceiver<Notification>,
+    connection: JoinHandle<Result<(), PgError>>,
+}
+
+impl Listening {
+    async fn start(pg_config: &tokio_postgres::Config) -> Result<Listening, PostgresStorageError> {
+        let (client, mut connection) = pg_config.connect(NoTls).await?;
+        let (sender, notifications) = mpsc::unbounded_channel();
+        let connection = tokio::spawn(async move {
+            let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+            while let Some(message) = messages.next().await {
+                if let AsyncMessage::Notification(notification) = message? {
+                    if sender.send(notification).is_err() {
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        });
+        client
+            .batch_execute(&format!("LISTEN {ITEM_EVENTS_CHANNEL}"))
+            .await?;
+        info!("Listening for item events");
+
+        Ok(Listening {
+            _client: client,
+            notifications,
+            connection,
+        })
+    }
+}
+
+/// Publishes item events to subscribers of storage, see `PostgresStorage::listen_item_events`
+pub struct ItemEventsListener {
+    storage: Arc<PostgresStorage>,
+    pg_config: tokio_postgres::Config,
+    listening: Listening,
+}
+
+impl ItemEventsListener {
+    /// Publish events until token is cancelled
+    /// Lost connection is established again, but events sent meanwhile are lost, so subscribers
+    /// are cut off then, to start over
+    pub async fn run(mut self, token: CancellationToken) {
+        loop {
+            let notification = tokio::select! {
+                notification = self.listening.notifications.recv() => notification,
+                _ = token.cancelled() => return,
+            };
+            match notification {
+                Some(notification) => {
+                    // Notifications that piled up while previous batch was published
+                    let mut batch = vec![notification];
+                    while batch.len() < NOTIFICATIONS_BATCH_SIZE {
+                        match self.listening.notifications.try_recv() {
+                            Ok(notification) => batch.push(notification),
+                            Err(_) => break,
+                        }
+                    }
+                    self.publish(batch).await;
+                }
+                None => {
+                    if !self.reconnect(&token).await {
+                        return;
+                    }
+                    self.storage.cut_off_subscribers();
+                }
+            }
+        }
+    }
+
+    async fn publish(&self, batch: Vec<Notification>) {
+        let notifications = batch
+            .iter()
+            .filter_map(|notification| {
+                serde_json::from_str(notification.payload())
+                    .inspect_err(|error| {
+                        warn!(%error, payload = notification.payload(), "Skipping malformed item notification")
+                    })
+                    .ok()
+            })
+            .collect();
+        if let Err(error) = self.storage.publish_item_events(notifications).await {
+            // Subscribers miss these events, same as if connection was lost
+            warn!(%error, count = batch.len(), "Failed to load items of notifications, skipping them");
+            self.storage.cut_off_subscribers();
+        }
+    }
+
+    /// Returns false if token was cancelled before listening again
+    async fn reconnect(&mut self, token: &CancellationToken) -> bool {
+        let error = match (&mut self.listening.connection).await {
+            Ok(Err(e)) => e.into(),
+            _ => PostgresStorageError::ListenerClosed,
+        };
+        warn!(%error, "Lost connection listening for item events, reconnecting");
+
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {},
+                _ = token.cancelled() => return false,
+            }
+            match Listening::start(&self.pg_config).await {
+                Ok(listening) => {
+                    self.listening = listening;
+                    return true;
+                }
+                Err(error) => warn!(%error, ?delay, "Failed to listen for item events"),
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use deadpool_postgres::tokio_postgres::NoTls;
+    use tokio::sync::broadcast;
+
+    use crate::storage::model::{NewItem, Storage};
+    use crate::storage::pg::migrations;
+    use crate::storage::pg::tests::create_test_database_config;
+
+    async fn next_event(events: &mut broadcast::Receiver<ItemEvent>) -> ItemEvent {
+        tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    // This test requires external PostgreSQL instance, so it is ignored by default
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_cross_instance_events() {
+        let cfg = create_test_database_config().await;
+        let pg_config = cfg.get_pg_config().unwrap();
+
+        // Each instance has its own pool and listener, like separate processes would
+        let mut instances = vec![];
+        for _ in 0..2 {
+            let pool = cfg.create_pool(None, NoTls).unwrap();
+            migrations::migrate_up(&pool).await.unwrap();
+            let storage = Arc::new(PostgresStorage::new(pool).await.unwrap());
+            let listener = storage
+                .clone()
+                .listen_item_events(&pg_config)
+                .await
+                .unwrap();
+            tokio::spawn(listener.run(CancellationToken::new()));
+            instances.push(storage);
+        }
+        let (first, second) = (&instances[0], &instances[1]);
+        let mut first_events = first.subscribe();
+        let mut second_events = second.subscribe();
+
+        // Notifications of a single transaction come together, and are published as batch
+        let items = (0..3).map(|i| NewItem {
+            menu_item_id: 1.into(),
+            name: format!("cross instance item {i}"),
+            comment: "".into(),
+            created_at: Utc::now(),
+            forecast_ready_at: Utc::now(),
+        });
+        let added = first.add_items(1.into(), None, items).await.unwrap();
+        for item in &added {
+            let expected = ItemEvent::Added(item.clone());
+            assert_eq!(next_event(&mut second_events).await, expected);
+            // Instance gets its own changes the same way
+            assert_eq!(next_event(&mut first_events).await, expected);
+        }
+
+        let item_id = added[0].item_id.clone();
+        second
+            .remove_items(1.into(), [item_id.clone()].into_iter())
+            .await
+            .unwrap();
+        let expected = ItemEvent::Removed {
+            table_id: 1.into(),
+            item_id,
+        };
+        assert_eq!(next_event(&mut first_events).await, expected);
+        assert_eq!(next_event(&mut second_events).await, expected);
+    }
+
+    // This test requires external PostgreSQL instance, so it is ignored by default
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_listener_reconnects() {
+        let cfg = create_test_database_config().await;
+        let pool = cfg.create_pool(None, NoTls).unwrap();
+        migrations::migrate_up(&pool).await.unwrap();
+        let storage = Arc::new(PostgresStorage::new(pool.clone()).await.unwrap());
+        let listener = storage
+            .clone()
+            .listen_item_events(&cfg.get_pg_config().unwrap())
+            .await
+            .unwrap();
+        tokio::spawn(listener.run(CancellationToken::new()));
+        let mut events = storage.subscribe();
+
+        pool.get()
+            .await
+            .unwrap()
+            .execute(
+                // language=PostgreSQL
+                "
+                    SELECT
+                        pg_terminate_backend(pid)
+                    FROM
+                        pg_stat_activity
+                    WHERE
+                        datname = current_database()
+                        AND
+                        query = 'LISTEN item_events'
+                ",
+                &[],
+            )
+            .await
+            .unwrap();
+
+        // Subscriber could have missed something while listener was away
+        let result = tokio::time::timeout(Duration::from_secs(10), events.recv())
+            .await
+            .unwrap();
+        assert_eq!(result, Err(broadcast::error::RecvError::Closed));
+
+        let mut events = storage.subscribe();
+        let item = NewItem {
+            menu_item_id: 1.into(),
+            name: "after reconnect".into(),
+            comment: "".into(),
+            created_at: Utc::now(),
+            forecast_ready_at: Utc::now(),
+        };
+        let added = storage
+            .add_items(1.into(), None, [item].into_iter())
+            .await
+            .unwrap();
+        assert_eq!(
+            next_event(&mut events).await,
+            ItemEvent::Added(added[0].clone())
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use tokio_postgres::IsolationLevel;
+use tracing::info;
+
+use super::PostgresStorageError;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    sql: &'static str,
+}
+
+/// All known migrations, ordered by version
+/// Released migrations should never be edited, any schema change should go to new migration
+/// Migrations should be additive, previous version keeps running against migrated schema during rollout
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_items",
+        // IF NOT EXISTS is here to adopt databases created with `init_db` before migrations were tracked
+        // Index name is the one PostgreSQL generated for `CREATE INDEX ON items (table_id)`
+        // language=PostgreSQL
+        sql: "
+        CREATE TABLE IF NOT EXISTS
+            items
+        (
+            item_id SERIAL PRIMARY KEY,
+            table_id INT NOT NULL,
+            name TEXT NOT NULL,
+            comment TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL,
+            forecast_ready_at TIMESTAMPTZ NOT NULL
+        );
+
+        -- All requests operate on a single table_id
+        -- TODO Make it part of primary key? Partition? For small dataset should not matter
+        CREATE INDEX IF NOT EXISTS items_table_id_idx ON items (table_id);
+    ",
+    },
+    Migration {
+        version: 2,
+        name: "create_idempotency_keys",
+        // language=PostgreSQL
+        sql: "
+            CREATE TABLE
+                idempotency_keys
+            (
+                table_id INT NOT NULL,
+                key TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                -- Items created by first call with this key
+                item_ids INT[] NOT NULL,
+                PRIMARY KEY (table_id, key)
+            );
+        ",
+    },
+    Migration {
+        version: 3,
+        name: "add_items_status",
+        // Existing items are considered just ordered
+        // language=PostgreSQL
+        sql: "
+            ALTER TABLE
+                items
+            ADD COLUMN
+                status TEXT NOT NULL DEFAULT 'ordered'
+                CHECK (status IN ('ordered', 'cooking', 'ready', 'served')),
+            ADD COLUMN
+                cooking_at TIMESTAMPTZ,
+            ADD COLUMN
+                ready_at TIMESTAMPTZ,
+            ADD COLUMN
+                served_at TIMESTAMPTZ;
+        ",
+    },
+    Migration {
+        version: 4,
+        name: "create_menu_items",
+        // Items are validated against menu by service, so there's no foreign key,
+        // and existing items are left without menu item
+        // language=PostgreSQL
+        sql: "
+            CREATE TABLE
+                menu_items
+            (
+                menu_item_id SERIAL PRIMARY KEY,
+                name TEXT NOT NULL,
+                category TEXT NOT NULL,
+                available BOOL NOT NULL
+            );
+
+            ALTER TABLE
+                items
+            ADD COLUMN
+                menu_item_id INT;
+        ",
+    },
+    Migration {
+        version: 5,
+        name: "create_restaurant_tables",
+        // Named so to not be confused with tables of database itself
+        // Tables of existing items are not registered, they should be added before taking new orders
+        // language=PostgreSQL
+        sql: "
+            CREATE TABLE
+                restaurant_tables
+            (
+                table_id SERIAL PRIMARY KEY,
+                name TEXT NOT NULL,
+                seats INT NOT NULL,
+                section TEXT NOT NULL,
+                active BOOL NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 6,
+        name: "create_table_versions",
+        // Versions are not in restaurant_tables, as items of unregistered tables change as well
+        // Existing items are left at version 0, they are only listed when client resets
+        // language=PostgreSQL
+        sql: "
+            CREATE TABLE
+                table_versions
+            (
+                table_id INT PRIMARY KEY,
+                version BIGINT NOT NULL
+            );
+
+            ALTER TABLE
+                items
+            ADD COLUMN
+                version BIGINT NOT NULL DEFAULT 0;
+
+            -- Removed items are kept here, so changes could report them
+            CREATE TABLE
+                removed_items
+            (
+                table_id INT NOT NULL,
+                item_id INT NOT NULL,
+                version BIGINT NOT NULL,
+                PRIMARY KEY (table_id, item_id)
+            );
+        ",
+    },
+    Migration {
+        version: 7,
+        name: "add_removed_items_retention",
+        // Existing tombstones are kept for whole retention from now on
+        // Default is for previous version, which does not set removal time during rollout
+        // language=PostgreSQL
+        sql: "
+            ALTER TABLE
+                removed_items
+            ADD COLUMN
+                removed_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+            -- Latest version of deleted tombstones, changes since earlier versions are incomplete
+            ALTER TABLE
+                table_versions
+            ADD COLUMN
+                pruned_version BIGINT NOT NULL DEFAULT 0;
+        ",
+    },
+];
+
+/// Version of schema this build expects
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum MigrationState {
+    Pending,
+    Applied(DateTime<Utc>),
+    /// Applied to database, but not known to this build, most probably DB was migrated by newer version
+    Unknown(DateTime<Utc>),
+}
+
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: i32,
+    pub name: String,
+    pub state: MigrationState,
+}
+
+async fn ensure_migrations_table(pool: &Pool) -> Result<(), PostgresStorageError> {
+    let db = pool.get().await?;
+    db.batch_execute(
+        // language=PostgreSQL
+        "
+            CREATE TABLE IF NOT EXISTS
+                schema_migrations
+            (
+                version INT PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at TIMESTAMPTZ NOT NULL
+            );
+        ",
+    )
+    .await?;
+    Ok(())
+}
+
+/// Report every known migration, and every applied one, in version order
+pub async fn status(pool: &Pool) -> Result<Vec<MigrationStatus>, PostgresStorageError> {
+    ensure_migrations_table(pool).await?;
+
+    let db = pool.get().await?;
+    let rows = db
+        .query(
+            // language=PostgreSQL
+            "
+                SELECT
+                    version,
+                    name,
+                    applied_at
+                FROM
+                    schema_migrations
+                ORDER BY
+                    version
+            ",
+            &[],
+        )
+        .await?;
+
+    let mut applied = rows
+        .into_iter()
+        .map(|row| -> Result<_, PostgresStorageError> {
+            Ok((
+                row.try_get::<_, i32>("version")?,
+                row.try_get::<_, String>("name")?,
+                row.try_get::<_, DateTime<Utc>>("applied_at")?,
+            ))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut result = MIGRATIONS
+        .iter()
+        .map(|migration| {
+            let applied_at = applied
+                .iter()
+                .find(|(version, _, _)| *version == migration.version)
+                .map(|(_, _, applied_at)| *applied_at);
+            MigrationStatus {
+                version: migration.version,
+                name: migration.name.to_string(),
+                state: match applied_at {
+                    Some(applied_at) => MigrationState::Applied(applied_at),
+                    None => MigrationState::Pending,
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+
+    applied.retain(|(version, _, _)| !MIGRATIONS.iter().any(|m| m.version == *version));
+    result.extend(
+        applied
+            .into_iter()
+            .map(|(version, name, applied_at)| MigrationStatus {
+                version,
+                name,
+                state: MigrationState::Unknown(applied_at),
+            }),
+    );
+    result.sort_by_key(|s| s.version);
+
+    Ok(result)
+}
+
+/// Apply all pending migrations, each in separate transaction. Safe to run repeatedly and concurrently.
+/// Returns versions of applied migrations
+pub async fn migrate_up(pool: &Pool) -> Result<Vec<i32>, PostgresStorageError> {
+    ensure_migrations_table(pool).await?;
+
+    let mut db = pool.get().await?;
+    let mut applied = vec![];
+
+    for migration in MIGRATIONS {
+        let txn = db
+            .build_transaction()
+            .isolation_level(IsolationLevel::Serializable)
+            .start()
+            .await?;
+
+        // Concurrent migrators would wait for each other here, and then see already applied migration
+        txn.batch_execute(
+            // language=PostgreSQL
+            "LOCK TABLE schema_migrations IN EXCLUSIVE MODE",
+        )
+        .await?;
+
+        let max_applied: Option<i32> = txn
+            .query_one(
+                // language=PostgreSQL
+                "SELECT MAX(version) FROM schema_migrations",
+                &[],
+            )
+            .await?
+            .try_get(0)?;
+        if let Some(max_applied) = max_applied {
+            if max_applied > latest_version() {
+                return Err(PostgresStorageError::UnknownMigration(max_applied));
+            }
+            if max_applied >= migration.version {
+                continue;
+            }
+        }
+
+        info!(
+            version = migration.version,
+            name = migration.name,
+            "Applying migration"
+        );
+        txn.batch_execute(migration.sql).await?;
+        txn.execute(
+            // language=PostgreSQL
+            "
+                INSERT INTO
+                    schema_migrations
+                    (version, name, applied_at)
+                VALUES
+                    ($1, $2, now())
+            ",
+            &[&migration.version, &migration.name],
+        )
+        .await?;
+        txn.commit().await?;
+
+        applied.push(migration.version);
+    }
+
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::pg::tests::create_test_database;
+
+    // This test requires external PostgreSQL instance, so it is ignored by default
+    #[tokio::test]
+    #[ignore]
+    async fn test_migrate_up_twice() {
+        let pool = create_test_database().await;
+
+        let status_before = status(&pool).await.unwrap();
+        assert!(status_before
+            .iter()
+            .all(|s| s.state == MigrationState::Pending));
+
+        let applied = migrate_up(&pool).await.unwrap();
+        assert_eq!(
+            applied,
+            MIGRATIONS.iter().map(|m| m.version).collect::<Vec<_>>()
+        );
+        assert!(migrate_up(&pool).await.unwrap().is_empty());
+
+        let status_after = status(&pool).await.unwrap();
+        assert_eq!(status_after.len(), MIGRATIONS.len());
+        assert!(status_after
+            .iter()
+            .all(|s| matches!(s.state, MigrationState::Applied(_))));
+    }
+
+    // This test requires external PostgreSQL instance, so it is ignored by default
+    #[tokio::test]
+    #[ignore]
+    async fn test_unknown_migration() {
+        let pool = create_test_database().await;
+        migrate_up(&pool).await.unwrap();
+
+        let future_version = latest_version() + 1;
+        pool.get()
+            .await
+            .unwrap()
+            .execute(
+                // language=PostgreSQL
+                "
+                    INSERT INTO
+                        schema_migrations
+                        (version, name, applied_at)
+                    VALUES
+                        ($1, 'from_the_future', now())
+                ",
+                &[&future_version],
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            migrate_up(&pool).await,
+            Err(PostgresStorageError::UnknownMigration(v)) if v == future_version
+        ));
+        let status = status(&pool).await.unwrap();
+        assert!(matches!(
+            status.last(),
+            Some(MigrationStatus { version, state: MigrationState::Unknown(_), .. }) if *version == future_version
+        ));
+    }
+}
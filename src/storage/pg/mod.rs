@@ -0,0 +1,1154 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use deadpool_postgres::{Client as PoolClient, Pool, PoolError};
+use derive_more::From;
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tokio_postgres::{types::Type, Client, Error as PgError, IsolationLevel, Transaction};
+use tracing::instrument;
+
+pub use self::events::ItemEventsListener;
+use self::events::ItemNotification;
+use self::queries::*;
+use self::query::Query;
+pub use self::retry::{RetryPolicy, RetryStats, RetryStatsSnapshot};
+use super::model::*;
+
+mod events;
+pub mod migrations;
+mod queries;
+mod query;
+mod retry;
+pub(crate) mod rows;
+pub mod schema;
+mod types;
+
+#[derive(Debug, Error, From)]
+pub enum PostgresStorageError {
+    #[error(transparent)]
+    PoolError(PoolError),
+    #[error(transparent)]
+    DbError(PgError),
+    #[error("column `{0}` not found in result set, this is most probably a bug, mismatch between query and parser")]
+    #[from(ignore)]
+    ColumnNotFound(&'static str),
+    #[error("database has migration {0} unknown to this version, it was probably migrated by newer version")]
+    #[from(ignore)]
+    UnknownMigration(i32),
+    #[error("database schema version {found:?} is older than expected {expected}, database should be migrated with this version")]
+    #[from(ignore)]
+    IncompatibleSchemaVersion { expected: i32, found: Option<i32> },
+    #[error("column `{table}.{column}` has type {found:?}, expected {expected}")]
+    #[from(ignore)]
+    IncompatibleColumn {
+        table: &'static str,
+        column: &'static str,
+        expected: &'static str,
+        found: Option<String>,
+    },
+    #[error("column `{column}` has type {found} incompatible with parser")]
+    #[from(ignore)]
+    IncompatibleColumnType { column: &'static str, found: Type },
+    #[error("statement returns {0} columns, but none expected")]
+    #[from(ignore)]
+    UnexpectedColumns(usize),
+    #[error("statement has {found} parameters, expected {expected}")]
+    #[from(ignore)]
+    ParamCountMismatch { expected: usize, found: usize },
+    #[error("parameter ${index} has type {found} incompatible with query params")]
+    #[from(ignore)]
+    IncompatibleParamType { index: usize, found: Type },
+    #[error("preflight check for query {query} failed: {cause}")]
+    #[from(ignore)]
+    Preflight {
+        query: &'static str,
+        cause: Box<PostgresStorageError>,
+    },
+    #[error("item events listener connection closed")]
+    #[from(ignore)]
+    ListenerClosed,
+}
+
+/// Max forecasts updated in a single transaction
+const FORECASTS_BATCH_SIZE: usize = 500;
+
+pub struct PostgresStorage {
+    pool: Pool,
+    retry_policy: RetryPolicy,
+    retry_stats: Arc<RetryStats>,
+    /// Fed by `ItemEventsListener`, replaced to cut off subscribers when events could be lost
+    events: Mutex<broadcast::Sender<ItemEvent>>,
+}
+
+impl PostgresStorage {
+    /// Refuses to construct storage on top of database with incompatible schema,
+    /// or if any of queries does not match it
+    pub async fn new(pool: Pool) -> Result<PostgresStorage, PostgresStorageError> {
+        schema::check_schema(&pool).await?;
+        let storage = PostgresStorage {
+            pool,
+            retry_policy: Default::default(),
+            retry_stats: Default::default(),
+            events: Mutex::new(broadcast::channel(ITEM_EVENTS_CAPACITY).0),
+        };
+        storage.preflight().await?;
+        Ok(storage)
+    }
+
+    pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> PostgresStorage {
+        PostgresStorage {
+            retry_policy,
+            ..self
+        }
+    }
+
+    /// Counters of retried transactions, updated as storage is used
+    pub fn retry_stats(&self) -> Arc<RetryStats> {
+        self.retry_stats.clone()
+    }
+
+    /// Prepare every query, and check its parameters and result columns against Rust types
+    /// This way bad deploy would fail on start, and not on first customer order
+    pub async fn preflight(&self) -> Result<(), PostgresStorageError> {
+        let db = self.get_db_client().await?;
+        preflight_all(&db).await
+    }
+
+    async fn with_retry<T, F, Fut>(
+        &self,
+        op: &'static str,
+        attempt: F,
+    ) -> Result<T, PostgresStorageError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, PostgresStorageError>>,
+    {
+        retry::with_retry(&self.retry_policy, &self.retry_stats, op, attempt).await
+    }
+
+    /// Idempotency keys are not used after retention window, but stay in database until this is called
+    /// Returns count of deleted keys
+    pub async fn delete_expired_idempotency_keys(&self) -> Result<u64, PostgresStorageError> {
+        let params = ExpiredIdempotencyKeysParams {
+            expired_before: Utc::now() - idempotency_key_retention(),
+        };
+        self.with_retry("delete_expired_idempotency_keys", || async {
+            let mut db = self.get_db_client().await?;
+            let txn = Self::start_transaction(&mut db).await?;
+            // COUNT always returns a row
+            let deleted = DeleteExpiredIdempotencyKeys::query_opt(&txn, &params)
+                .await?
+                .map_or(0, |count| count.deleted);
+            txn.commit().await?;
+            Ok(deleted as u64)
+        })
+        .await
+    }
+
+    /// Removed items are reported by `changes_since` until this deletes them after retention window
+    /// Returns count of deleted tombstones
+    pub async fn delete_expired_tombstones(&self) -> Result<u64, PostgresStorageError> {
+        let params = ExpiredTombstonesParams {
+            expired_before: Utc::now() - tombstone_retention(),
+        };
+        self.with_retry("delete_expired_tombstones", || async {
+            let mut db = self.get_db_client().await?;
+            let txn = Self::start_transaction(&mut db).await?;
+            // COUNT always returns a row
+            let deleted = DeleteExpiredTombstones::query_opt(&txn, &params)
+                .await?
+                .map_or(0, |count| count.deleted);
+            txn.commit().await?;
+            Ok(deleted as u64)
+        })
+        .await
+    }
+
+    async fn get_db_client(&self) -> Result<PoolClient, PostgresStorageError> {
+        Ok(self.pool.get().await?)
+    }
+
+    async fn start_transaction(db: &mut Client) -> Result<Transaction<'_>, PostgresStorageError> {
+        Ok(db
+            .build_transaction()
+            .isolation_level(IsolationLevel::Serializable)
+            .start()
+            .await?)
+    }
+
+    async fn start_readonly_transaction(
+        db: &mut Client,
+    ) -> Result<Transaction<'_>, PostgresStorageError> {
+        Ok(db
+            .build_transaction()
+            .isolation_level(IsolationLevel::Serializable)
+            .read_only(true)
+            .deferrable(true)
+            .start()
+            .await?)
+    }
+}
+
+/// Single attempt of every operation, each runs exactly one transaction
+impl PostgresStorage {
+    async fn add_items_once(
+        &self,
+        idempotency_key: Option<&IdempotencyKey>,
+        params: &InsertItemsParams,
+    ) -> Result<Vec<ItemInfo>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_transaction(&mut db).await?;
+
+        let mut items = if params.names.is_empty() {
+            vec![]
+        } else {
+            InsertItems::query(&txn, params).await?
+        };
+        // RETURNING does not guarantee any order, but ids were generated in order of items
+        items.sort_by_key(|item| item.item_id.0);
+
+        if let Some(key) = idempotency_key {
+            let now = Utc::now();
+            let claimed = ClaimIdempotencyKey::query_opt(
+                &txn,
+                &ClaimIdempotencyKeyParams {
+                    table_id: params.table_id,
+                    key: key.0.clone(),
+                    created_at: now,
+                    item_ids: items.iter().map(|item| item.item_id.0).collect(),
+                    expired_before: now - idempotency_key_retention(),
+                },
+            )
+            .await?;
+            if claimed.is_none() {
+                // This is a replay, items inserted above should not be visible to anyone
+                txn.rollback().await?;
+                // Key could expire between claim and this, then there's nothing to replay
+                return Ok(Self::idempotent_items(&mut db, params.table_id, key)
+                    .await?
+                    .unwrap_or_default());
+            }
+        }
+
+        Self::notify_item_events(
+            &txn,
+            items.iter().map(|item| ItemNotification::Added {
+                table_id: params.table_id,
+                item_id: item.item_id.0,
+            }),
+        )
+        .await?;
+
+        txn.commit().await?;
+
+        Ok(items)
+    }
+
+    async fn idempotent_items(
+        db: &mut Client,
+        table_id: i32,
+        idempotency_key: &IdempotencyKey,
+    ) -> Result<Option<Vec<ItemInfo>>, PostgresStorageError> {
+        let txn = Self::start_readonly_transaction(db).await?;
+
+        let used = GetIdempotencyKey::query_opt(
+            &txn,
+            &IdempotencyKeyParams {
+                table_id,
+                key: idempotency_key.0.clone(),
+                expired_before: Utc::now() - idempotency_key_retention(),
+            },
+        )
+        .await?;
+        let items = match used {
+            Some(used) => Some(
+                GetItems::query(
+                    &txn,
+                    &ItemsParams {
+                        table_id,
+                        item_ids: used.item_ids,
+                    },
+                )
+                .await?,
+            ),
+            None => None,
+        };
+
+        txn.commit().await?;
+
+        Ok(items)
+    }
+
+    async fn get_idempotent_items_once(
+        &self,
+        table_id: i32,
+        idempotency_key: &IdempotencyKey,
+    ) -> Result<Option<Vec<ItemInfo>>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        Self::idempotent_items(&mut db, table_id, idempotency_key).await
+    }
+
+    async fn remove_items_once(
+        &self,
+        params: &ItemsParams,
+    ) -> Result<Vec<RemovedItem>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_transaction(&mut db).await?;
+
+        let removed = RemoveItems::query(&txn, params).await?;
+        Self::notify_item_events(
+            &txn,
+            removed.iter().map(|row| ItemNotification::Removed {
+                table_id: params.table_id,
+                item_id: row.item_id.0,
+            }),
+        )
+        .await?;
+
+        txn.commit().await?;
+
+        Ok(removed)
+    }
+
+    async fn list_items_once(
+        &self,
+        params: &TableParams,
+    ) -> Result<Vec<ItemInfoShort>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_readonly_transaction(&mut db).await?;
+
+        let items = ListItems::query(&txn, params).await?;
+
+        txn.commit().await?;
+
+        Ok(items)
+    }
+
+    async fn changes_since_once(
+        &self,
+        params: &TableVersionParams,
+    ) -> Result<ItemChanges, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_readonly_transaction(&mut db).await?;
+
+        let table_params = TableParams {
+            table_id: params.table_id,
+        };
+        let (version, pruned_version) = GetTableVersion::query_opt(&txn, &table_params)
+            .await?
+            .map_or((0, 0), |row| (row.version, row.pruned_version));
+        let changes =
+            if params.since <= 0 || params.since > version || params.since < pruned_version {
+                ItemChanges {
+                    version: TableVersion(version),
+                    reset: true,
+                    changed: ListItems::query(&txn, &table_params).await?,
+                    removed: vec![],
+                }
+            } else {
+                ItemChanges {
+                    version: TableVersion(version),
+                    reset: false,
+                    changed: ListChangedItems::query(&txn, params).await?,
+                    removed: ListRemovedItems::query(&txn, params)
+                        .await?
+                        .into_iter()
+                        .map(|row| row.item_id)
+                        .collect(),
+                }
+            };
+
+        txn.commit().await?;
+
+        Ok(changes)
+    }
+
+    async fn table_version_once(
+        &self,
+        params: &TableParams,
+    ) -> Result<TableVersion, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_readonly_transaction(&mut db).await?;
+
+        let version = GetTableVersion::query_opt(&txn, params).await?;
+
+        txn.commit().await?;
+
+        Ok(TableVersion(version.map_or(0, |row| row.version)))
+    }
+
+    async fn get_item_once(
+        &self,
+        params: &ItemParams,
+    ) -> Result<Option<ItemInfo>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_readonly_transaction(&mut db).await?;
+
+        let item = GetItem::query_opt(&txn, params).await?;
+
+        txn.commit().await?;
+
+        Ok(item)
+    }
+
+    async fn get_versioned_item_once(
+        &self,
+        params: &ItemParams,
+    ) -> Result<(Option<ItemInfo>, TableVersion), PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_readonly_transaction(&mut db).await?;
+
+        let item = GetItem::query_opt(&txn, params).await?;
+        let table_params = TableParams {
+            table_id: params.table_id,
+        };
+        let version = GetTableVersion::query_opt(&txn, &table_params).await?;
+
+        txn.commit().await?;
+
+        Ok((item, TableVersion(version.map_or(0, |row| row.version))))
+    }
+
+    async fn advance_item_once(
+        &self,
+        params: &ItemParams,
+        to: ItemStatus,
+        at: DateTime<Utc>,
+    ) -> Result<AdvancedItem, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_transaction(&mut db).await?;
+
+        let Some(mut item) = GetItem::query_opt(&txn, params).await? else {
+            return Ok(AdvancedItem::NotFound);
+        };
+        if let Err(from) = item.advance(to, at) {
+            return Ok(AdvancedItem::IllegalTransition { from });
+        }
+        UpdateItemStatus::execute(
+            &txn,
+            &ItemStatusParams {
+                table_id: params.table_id,
+                item_id: params.item_id,
+                status: item.status,
+                cooking_at: item.cooking_at,
+                ready_at: item.ready_at,
+                served_at: item.served_at,
+            },
+        )
+        .await?;
+        Self::notify_item_events(
+            &txn,
+            [ItemNotification::Advanced {
+                table_id: params.table_id,
+                item_id: params.item_id,
+            }],
+        )
+        .await?;
+
+        txn.commit().await?;
+
+        Ok(AdvancedItem::Advanced(item))
+    }
+
+    async fn add_menu_items_once(
+        &self,
+        params: &InsertMenuItemsParams,
+    ) -> Result<Vec<MenuItem>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_transaction(&mut db).await?;
+
+        let mut items = InsertMenuItems::query(&txn, params).await?;
+        // Same as for items, ids were generated in order
+        items.sort_by_key(|item| item.menu_item_id.0);
+
+        txn.commit().await?;
+
+        Ok(items)
+    }
+
+    async fn list_menu_once(&self) -> Result<Vec<MenuItem>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_readonly_transaction(&mut db).await?;
+
+        let items = ListMenuItems::query(&txn, &()).await?;
+
+        txn.commit().await?;
+
+        Ok(items)
+    }
+
+    async fn get_menu_items_once(
+        &self,
+        params: &MenuItemsParams,
+    ) -> Result<Vec<MenuItem>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_readonly_transaction(&mut db).await?;
+
+        let items = GetMenuItems::query(&txn, params).await?;
+
+        txn.commit().await?;
+
+        Ok(items)
+    }
+
+    async fn set_menu_item_available_once(
+        &self,
+        params: &MenuItemAvailableParams,
+    ) -> Result<Option<MenuItem>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_transaction(&mut db).await?;
+
+        let item = SetMenuItemAvailable::query_opt(&txn, params).await?;
+
+        txn.commit().await?;
+
+        Ok(item)
+    }
+
+    async fn kitchen_stats_once(
+        &self,
+        params: &SinceParams,
+    ) -> Result<KitchenStats, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_readonly_transaction(&mut db).await?;
+
+        let prep_times = GetPrepTimes::query(&txn, params).await?;
+        let queued = CountQueuedItems::query(&txn, params).await?;
+
+        txn.commit().await?;
+
+        Ok(KitchenStats {
+            prep_times: prep_times
+                .into_iter()
+                .map(|row| PrepTime {
+                    menu_item_id: row.menu_item_id,
+                    completed: row.completed,
+                    average: Duration::milliseconds(row.average_ms),
+                })
+                .collect(),
+            // COUNT without GROUP BY always returns single row
+            queued: queued.first().map(|row| row.queued).unwrap_or(0),
+        })
+    }
+
+    async fn outstanding_items_once(
+        &self,
+        params: &SinceParams,
+    ) -> Result<Vec<ItemInfo>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_readonly_transaction(&mut db).await?;
+
+        let items = ListOutstandingItems::query(&txn, params).await?;
+
+        txn.commit().await?;
+
+        Ok(items)
+    }
+
+    async fn update_forecasts_once(
+        &self,
+        params: &ForecastsParams,
+    ) -> Result<u64, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_transaction(&mut db).await?;
+
+        let updated = UpdateForecasts::query(&txn, params).await?;
+        Self::notify_item_events(
+            &txn,
+            updated.iter().map(|forecast| ItemNotification::Forecast {
+                table_id: forecast.table_id,
+                item_id: forecast.item_id,
+            }),
+        )
+        .await?;
+
+        txn.commit().await?;
+
+        Ok(updated.len() as u64)
+    }
+
+    async fn add_tables_once(
+        &self,
+        params: &InsertTablesParams,
+    ) -> Result<Vec<Table>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_transaction(&mut db).await?;
+
+        let mut tables = InsertTables::query(&txn, params).await?;
+        // Same as for items, ids were generated in order
+        tables.sort_by_key(|table| table.table_id.0);
+
+        txn.commit().await?;
+
+        Ok(tables)
+    }
+
+    async fn list_tables_once(&self) -> Result<Vec<Table>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_readonly_transaction(&mut db).await?;
+
+        let tables = ListTables::query(&txn, &()).await?;
+
+        txn.commit().await?;
+
+        Ok(tables)
+    }
+
+    async fn get_table_once(
+        &self,
+        params: &TableParams,
+    ) -> Result<Option<Table>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_readonly_transaction(&mut db).await?;
+
+        let table = GetTable::query_opt(&txn, params).await?;
+
+        txn.commit().await?;
+
+        Ok(table)
+    }
+
+    async fn set_table_active_once(
+        &self,
+        params: &TableActiveParams,
+    ) -> Result<Option<Table>, PostgresStorageError> {
+        let mut db = self.get_db_client().await?;
+        let txn = Self::start_transaction(&mut db).await?;
+
+        let table = SetTableActive::query_opt(&txn, params).await?;
+
+        txn.commit().await?;
+
+        Ok(table)
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    type Error = PostgresStorageError;
+
+    #[instrument(skip(self, items))]
+    async fn add_items(
+        &self,
+        table_id: TableId,
+        idempotency_key: Option<IdempotencyKey>,
+        items: impl Iterator<Item = NewItem> + Send,
+    ) -> Result<Vec<ItemInfo>, Self::Error> {
+        let mut params = InsertItemsParams {
+            table_id: table_id.0,
+            menu_item_ids: vec![],
+            names: vec![],
+            comments: vec![],
+            created_at: vec![],
+            forecast_ready_at: vec![],
+        };
+        for item in items {
+            params.menu_item_ids.push(item.menu_item_id.0);
+            params.names.push(item.name);
+            params.comments.push(item.comment);
+            params.created_at.push(item.created_at);
+            params.forecast_ready_at.push(item.forecast_ready_at);
+        }
+        if params.names.is_empty() && idempotency_key.is_none() {
+            return Ok(vec![]);
+        }
+
+        self.with_retry("add_items", || {
+            self.add_items_once(idempotency_key.as_ref(), &params)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_idempotent_items(
+        &self,
+        table_id: TableId,
+        idempotency_key: IdempotencyKey,
+    ) -> Result<Option<Vec<ItemInfo>>, Self::Error> {
+        self.with_retry("get_idempotent_items", || {
+            self.get_idempotent_items_once(table_id.0, &idempotency_key)
+        })
+        .await
+    }
+
+    #[instrument(skip(self, item_ids))]
+    async fn remove_items(
+        &self,
+        table_id: TableId,
+        item_ids: impl Iterator<Item = ItemId> + Send,
+    ) -> Result<RemovedItems, Self::Error> {
+        let item_ids = item_ids.collect::<Vec<_>>();
+        let params = ItemsParams {
+            table_id: table_id.0,
+            item_ids: item_ids.iter().map(|id| id.0).collect(),
+        };
+
+        let removed = self
+            .with_retry("remove_items", || self.remove_items_once(&params))
+            .await?;
+
+        Ok(RemovedItems::from_requested(item_ids, |id| {
+            removed.iter().any(|row| &row.item_id == id)
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn list_items(&self, table_id: TableId) -> Result<Vec<ItemInfoShort>, Self::Error> {
+        let params = TableParams {
+            table_id: table_id.0,
+        };
+        self.with_retry("list_items", || self.list_items_once(&params))
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn changes_since(
+        &self,
+        table_id: TableId,
+        since: TableVersion,
+    ) -> Result<ItemChanges, Self::Error> {
+        let params = TableVersionParams {
+            table_id: table_id.0,
+            since: since.0,
+        };
+        self.with_retry("changes_since", || self.changes_since_once(&params))
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn table_version(&self, table_id: TableId) -> Result<TableVersion, Self::Error> {
+        let params = TableParams {
+            table_id: table_id.0,
+        };
+        self.with_retry("table_version", || self.table_version_once(&params))
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+    ) -> Result<Option<ItemInfo>, Self::Error> {
+        let params = ItemParams {
+            table_id: table_id.0,
+            item_id: item_id.0,
+        };
+        self.with_retry("get_item", || self.get_item_once(&params))
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_versioned_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+    ) -> Result<(Option<ItemInfo>, TableVersion), Self::Error> {
+        let params = ItemParams {
+            table_id: table_id.0,
+            item_id: item_id.0,
+        };
+        self.with_retry("get_versioned_item", || {
+            self.get_versioned_item_once(&params)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn advance_item(
+        &self,
+        table_id: TableId,
+        item_id: ItemId,
+        to: ItemStatus,
+        at: DateTime<Utc>,
+    ) -> Result<AdvancedItem, Self::Error> {
+        let params = ItemParams {
+            table_id: table_id.0,
+            item_id: item_id.0,
+        };
+        self.with_retry("advance_item", || self.advance_item_once(&params, to, at))
+            .await
+    }
+
+    #[instrument(skip(self, items))]
+    async fn add_menu_items(
+        &self,
+        items: impl Iterator<Item = NewMenuItem> + Send,
+    ) -> Result<Vec<MenuItem>, Self::Error> {
+        let mut params = InsertMenuItemsParams {
+            names: vec![],
+            categories: vec![],
+            available: vec![],
+        };
+        for item in items {
+            params.names.push(item.name);
+            params.categories.push(item.category);
+            params.available.push(item.available);
+        }
+        if params.names.is_empty() {
+            return Ok(vec![]);
+        }
+
+        self.with_retry("add_menu_items", || self.add_menu_items_once(&params))
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn list_menu(&self) -> Result<Vec<MenuItem>, Self::Error> {
+        self.with_retry("list_menu", || self.list_menu_once()).await
+    }
+
+    #[instrument(skip(self, menu_item_ids))]
+    async fn get_menu_items(
+        &self,
+        menu_item_ids: impl Iterator<Item = MenuItemId> + Send,
+    ) -> Result<Vec<MenuItem>, Self::Error> {
+        let params = MenuItemsParams {
+            menu_item_ids: menu_item_ids.map(|id| id.0).collect(),
+        };
+        self.with_retry("get_menu_items", || self.get_menu_items_once(&params))
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn set_menu_item_available(
+        &self,
+        menu_item_id: MenuItemId,
+        available: bool,
+    ) -> Result<Option<MenuItem>, Self::Error> {
+        let params = MenuItemAvailableParams {
+            menu_item_id: menu_item_id.0,
+            available,
+        };
+        self.with_retry("set_menu_item_available", || {
+            self.set_menu_item_available_once(&params)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn kitchen_stats(&self, since: DateTime<Utc>) -> Result<KitchenStats, Self::Error> {
+        let params = SinceParams { since };
+        self.with_retry("kitchen_stats", || self.kitchen_stats_once(&params))
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn outstanding_items(&self, since: DateTime<Utc>) -> Result<Vec<ItemInfo>, Self::Error> {
+        let params = SinceParams { since };
+        self.with_retry("outstanding_items", || self.outstanding_items_once(&params))
+            .await
+    }
+
+    #[instrument(skip(self, forecasts))]
+    async fn update_forecasts(
+        &self,
+        forecasts: impl Iterator<Item = ForecastUpdate> + Send,
+    ) -> Result<u64, Self::Error> {
+        // Forecasts are independent, so there's no need to update all of them atomically, and
+        // smaller transactions are less likely to conflict with kitchen advancing items.
+        // Each batch is for single table, as it bumps version of table, and so conflicts with
+        // every other change of it.
+        let mut forecasts = forecasts.collect::<Vec<_>>();
+        forecasts.sort_by_key(|forecast| forecast.table_id.0);
+        let batches = forecasts
+            .chunk_by(|a, b| a.table_id == b.table_id)
+            .flat_map(|table_forecasts| table_forecasts.chunks(FORECASTS_BATCH_SIZE));
+        let mut updated = 0;
+        for batch in batches {
+            let mut params = ForecastsParams {
+                table_ids: vec![],
+                item_ids: vec![],
+                forecast_ready_at: vec![],
+            };
+            for forecast in batch {
+                params.table_ids.push(forecast.table_id.0);
+                params.item_ids.push(forecast.item_id.0);
+                params.forecast_ready_at.push(forecast.forecast_ready_at);
+            }
+            updated += self
+                .with_retry("update_forecasts", || self.update_forecasts_once(&params))
+                .await?;
+        }
+        Ok(updated)
+    }
+
+    #[instrument(skip(self, tables))]
+    async fn add_tables(
+        &self,
+        tables: impl Iterator<Item = NewTable> + Send,
+    ) -> Result<Vec<Table>, Self::Error> {
+        let mut params = InsertTablesParams {
+            names: vec![],
+            seats: vec![],
+            sections: vec![],
+        };
+        for table in tables {
+            params.names.push(table.name);
+            params.seats.push(table.seats);
+            params.sections.push(table.section);
+        }
+        if params.names.is_empty() {
+            return Ok(vec![]);
+        }
+
+        self.with_retry("add_tables", || self.add_tables_once(&params))
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn list_tables(&self) -> Result<Vec<Table>, Self::Error> {
+        self.with_retry("list_tables", || self.list_tables_once())
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_table(&self, table_id: TableId) -> Result<Option<Table>, Self::Error> {
+        let params = TableParams {
+            table_id: table_id.0,
+        };
+        self.with_retry("get_table", || self.get_table_once(&params))
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn set_table_active(
+        &self,
+        table_id: TableId,
+        active: bool,
+    ) -> Result<Option<Table>, Self::Error> {
+        let params = TableActiveParams {
+            table_id: table_id.0,
+            active,
+        };
+        self.with_retry("set_table_active", || self.set_table_active_once(&params))
+            .await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ItemEvent> {
+        self.events.lock().unwrap().subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    use deadpool_postgres::tokio_postgres::NoTls;
+    use deadpool_postgres::Config;
+    use rand::RngCore;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::storage::testing::test_suite;
+
+    /// Creates fresh database, to isolate tests from each other
+    pub(super) async fn create_test_database() -> Pool {
+        create_test_database_config()
+            .await
+            .create_pool(None, NoTls)
+            .unwrap()
+    }
+
+    /// Same as `create_test_database`, for tests that need connections outside of pool
+    pub(super) async fn create_test_database_config() -> Config {
+        let dbidx = rand::thread_rng().next_u32();
+        let dbname = format!("postgres_storage_test_{dbidx:08x}");
+
+        let mut initial_cfg = Config::new();
+        initial_cfg.host = Some(env::var("PG_HOST").unwrap());
+        initial_cfg.port = Some(env::var("PG_PORT").unwrap().parse().unwrap());
+        initial_cfg.user = Some(env::var("PG_USER").unwrap());
+        initial_cfg.password = Some(env::var("PG_PASS").unwrap());
+        initial_cfg.dbname = Some("postgres".into());
+        {
+            let initial_pool = initial_cfg.create_pool(None, NoTls).unwrap();
+            let db = initial_pool.get().await.unwrap();
+            db.simple_query(
+                // CREATE DATABASE does not support parameters
+                // language=PostgreSQL
+                format!("CREATE DATABASE {dbname};").as_str(),
+            )
+            .await
+            .unwrap();
+        }
+
+        let mut cfg = initial_cfg.clone();
+        cfg.dbname = Some(dbname);
+        cfg
+    }
+
+    // This test requires external PostgreSQL instance, so it is ignored by default
+    #[test]
+    #[ignore]
+    fn test_pg_storage() {
+        test_suite(|| async {
+            // Running each test on a fresh database
+            let cfg = create_test_database_config().await;
+            let pool = cfg.create_pool(None, NoTls).unwrap();
+
+            migrations::migrate_up(&pool).await.unwrap();
+
+            let storage = Arc::new(PostgresStorage::new(pool).await.unwrap());
+            // Events are published by listener
+            let listener = storage
+                .clone()
+                .listen_item_events(&cfg.get_pg_config().unwrap())
+                .await
+                .unwrap();
+            tokio::spawn(listener.run(CancellationToken::new()));
+            storage
+        })
+        .unwrap()
+    }
+
+    // This test requires external PostgreSQL instance, so it is ignored by default
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_concurrent_idempotent_add() {
+        let pool = create_test_database().await;
+        migrations::migrate_up(&pool).await.unwrap();
+        let storage = Arc::new(PostgresStorage::new(pool).await.unwrap());
+
+        // Concurrent replays of same order conflict with each other, and could fail without retries
+        let key = IdempotencyKey::from("concurrent key".to_string());
+        let mut set = tokio::task::JoinSet::new();
+        for _ in 0..8 {
+            let storage = storage.clone();
+            let key = key.clone();
+            set.spawn(async move {
+                let item = NewItem {
+                    menu_item_id: 1.into(),
+                    name: "concurrent item".into(),
+                    comment: "".into(),
+                    created_at: Utc::now(),
+                    forecast_ready_at: Utc::now(),
+                };
+                storage
+                    .add_items(1.into(), Some(key), [item].into_iter())
+                    .await
+            });
+        }
+        let results = set
+            .join_all()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert!(results.iter().all(|items| items == &results[0]));
+        assert_eq!(storage.list_items(1.into()).await.unwrap().len(), 1);
+    }
+
+    // This test requires external PostgreSQL instance, so it is ignored by default
+    #[tokio::test]
+    #[ignore]
+    async fn test_expired_idempotency_key() {
+        let pool = create_test_database().await;
+        migrations::migrate_up(&pool).await.unwrap();
+        let storage = PostgresStorage::new(pool.clone()).await.unwrap();
+
+        let key = IdempotencyKey::from("old key".to_string());
+        let new_item = || NewItem {
+            menu_item_id: 1.into(),
+            name: "item".into(),
+            comment: "".into(),
+            created_at: Utc::now(),
+            forecast_ready_at: Utc::now(),
+        };
+        let added = storage
+            .add_items(1.into(), Some(key.clone()), [new_item()].into_iter())
+            .await
+            .unwrap();
+        assert_eq!(storage.delete_expired_idempotency_keys().await.unwrap(), 0);
+
+        pool.get()
+            .await
+            .unwrap()
+            .batch_execute(
+                // language=PostgreSQL
+                "UPDATE idempotency_keys SET created_at = created_at - INTERVAL '25 hours'",
+            )
+            .await
+            .unwrap();
+
+        // Expired key is not replayed, and is replaced with new one
+        let added_again = storage
+            .add_items(1.into(), Some(key.clone()), [new_item()].into_iter())
+            .await
+            .unwrap();
+        assert_ne!(added, added_again);
+        let replayed = storage
+            .add_items(1.into(), Some(key), [new_item()].into_iter())
+            .await
+            .unwrap();
+        assert_eq!(replayed, added_again);
+        assert_eq!(storage.delete_expired_idempotency_keys().await.unwrap(), 0);
+
+        pool.get()
+            .await
+            .unwrap()
+            .batch_execute(
+                // language=PostgreSQL
+                "UPDATE idempotency_keys SET created_at = created_at - INTERVAL '25 hours'",
+            )
+            .await
+            .unwrap();
+        assert_eq!(storage.delete_expired_idempotency_keys().await.unwrap(), 1);
+    }
+
+    // This test requires external PostgreSQL instance, so it is ignored by default
+    #[tokio::test]
+    #[ignore]
+    async fn test_expired_tombstones() {
+        let pool = create_test_database().await;
+        migrations::migrate_up(&pool).await.unwrap();
+        let storage = PostgresStorage::new(pool.clone()).await.unwrap();
+
+        let new_item = || NewItem {
+            menu_item_id: 1.into(),
+            name: "item".into(),
+            comment: "".into(),
+            created_at: Utc::now(),
+            forecast_ready_at: Utc::now(),
+        };
+        let added = storage
+            .add_items(1.into(), None, [new_item(), new_item()].into_iter())
+            .await
+            .unwrap();
+        let before = storage.table_version(1.into()).await.unwrap();
+        storage
+            .remove_items(1.into(), [added[0].item_id.clone()].into_iter())
+            .await
+            .unwrap();
+        let after_first = storage.table_version(1.into()).await.unwrap();
+        assert_eq!(storage.delete_expired_tombstones().await.unwrap(), 0);
+
+        pool.get()
+            .await
+            .unwrap()
+            .batch_execute(
+                // language=PostgreSQL
+                "UPDATE removed_items SET removed_at = removed_at - INTERVAL '25 hours'",
+            )
+            .await
+            .unwrap();
+        storage
+            .remove_items(1.into(), [added[1].item_id.clone()].into_iter())
+            .await
+            .unwrap();
+        assert_eq!(storage.delete_expired_tombstones().await.unwrap(), 1);
+
+        // First removal is forgotten, so cursor before it can't get complete changes
+        let changes = storage.changes_since(1.into(), before).await.unwrap();
+        assert!(changes.reset);
+        assert!(changes.changed.is_empty());
+        let changes = storage.changes_since(1.into(), after_first).await.unwrap();
+        assert!(!changes.reset);
+        assert_eq!(changes.removed, vec![added[1].item_id.clone()]);
+    }
+}
@@ -0,0 +1,1134 @@
+use chrono::{DateTime, Utc};
+use rows_parser_derive::RowsParser;
+use tokio_postgres::Client;
+
+use super::query::{query_params_struct, NoRows, Query};
+use super::PostgresStorageError;
+use crate::storage::model::{
+    ItemId, ItemInfo, ItemInfoShort, ItemStatus, MenuItem, MenuItemId, Table, TableId,
+};
+
+/// Preflight every query used by storage, new queries should be added here
+pub(super) async fn preflight_all(db: &Client) -> Result<(), PostgresStorageError> {
+    InsertItems::preflight(db).await?;
+    RemoveItems::preflight(db).await?;
+    ListItems::preflight(db).await?;
+    GetTableVersion::preflight(db).await?;
+    ListChangedItems::preflight(db).await?;
+    ListRemovedItems::preflight(db).await?;
+    GetItem::preflight(db).await?;
+    GetItems::preflight(db).await?;
+    GetItemsOfTables::preflight(db).await?;
+    UpdateItemStatus::preflight(db).await?;
+    GetIdempotencyKey::preflight(db).await?;
+    DeleteExpiredIdempotencyKeys::preflight(db).await?;
+    DeleteExpiredTombstones::preflight(db).await?;
+    ClaimIdempotencyKey::preflight(db).await?;
+    InsertMenuItems::preflight(db).await?;
+    ListMenuItems::preflight(db).await?;
+    GetMenuItems::preflight(db).await?;
+    SetMenuItemAvailable::preflight(db).await?;
+    GetPrepTimes::preflight(db).await?;
+    CountQueuedItems::preflight(db).await?;
+    ListOutstandingItems::preflight(db).await?;
+    UpdateForecasts::preflight(db).await?;
+    NotifyItemEvents::preflight(db).await?;
+    InsertTables::preflight(db).await?;
+    ListTables::preflight(db).await?;
+    GetTable::preflight(db).await?;
+    SetTableActive::preflight(db).await?;
+    Ok(())
+}
+
+// Rows of model types are parsed here, so model itself does not depend on PostgreSQL
+
+#[derive(RowsParser)]
+#[rows_parser(parser = ItemInfoShortParser, into = ItemInfoShort)]
+pub(super) struct ItemInfoShortRow {
+    #[rows_parser(native = i32)]
+    table_id: TableId,
+    #[rows_parser(native = i32)]
+    item_id: ItemId,
+    name: String,
+    status: ItemStatus,
+    forecast_ready_at: DateTime<Utc>,
+}
+
+impl From<ItemInfoShortRow> for ItemInfoShort {
+    fn from(row: ItemInfoShortRow) -> Self {
+        ItemInfoShort {
+            table_id: row.table_id,
+            item_id: row.item_id,
+            name: row.name,
+            status: row.status,
+            forecast_ready_at: row.forecast_ready_at,
+        }
+    }
+}
+
+#[derive(RowsParser)]
+#[rows_parser(parser = ItemInfoParser, into = ItemInfo)]
+pub(super) struct ItemInfoRow {
+    #[rows_parser(native = i32)]
+    table_id: TableId,
+    #[rows_parser(native = i32)]
+    item_id: ItemId,
+    menu_item_id: Option<MenuItemId>,
+    name: String,
+    comment: String,
+    created_at: DateTime<Utc>,
+    forecast_ready_at: DateTime<Utc>,
+    status: ItemStatus,
+    cooking_at: Option<DateTime<Utc>>,
+    ready_at: Option<DateTime<Utc>>,
+    served_at: Option<DateTime<Utc>>,
+}
+
+impl From<ItemInfoRow> for ItemInfo {
+    fn from(row: ItemInfoRow) -> Self {
+        ItemInfo {
+            table_id: row.table_id,
+            item_id: row.item_id,
+            menu_item_id: row.menu_item_id,
+            name: row.name,
+            comment: row.comment,
+            created_at: row.created_at,
+            forecast_ready_at: row.forecast_ready_at,
+            status: row.status,
+            cooking_at: row.cooking_at,
+            ready_at: row.ready_at,
+            served_at: row.served_at,
+        }
+    }
+}
+
+#[derive(RowsParser)]
+#[rows_parser(parser = MenuItemParser, into = MenuItem)]
+pub(super) struct MenuItemRow {
+    menu_item_id: MenuItemId,
+    name: String,
+    category: String,
+    available: bool,
+}
+
+impl From<MenuItemRow> for MenuItem {
+    fn from(row: MenuItemRow) -> Self {
+        MenuItem {
+            menu_item_id: row.menu_item_id,
+            name: row.name,
+            category: row.category,
+            available: row.available,
+        }
+    }
+}
+
+#[derive(RowsParser)]
+#[rows_parser(parser = TableParser, into = Table)]
+pub(super) struct TableRow {
+    #[rows_parser(native = i32)]
+    table_id: TableId,
+    name: String,
+    seats: i32,
+    section: String,
+    active: bool,
+}
+
+impl From<TableRow> for Table {
+    fn from(row: TableRow) -> Self {
+        Table {
+            table_id: row.table_id,
+            name: row.name,
+            seats: row.seats,
+            section: row.section,
+            active: row.active,
+        }
+    }
+}
+
+query_params_struct!(TableParams, (table_id, i32),);
+
+query_params_struct!(ItemParams, (table_id, i32), (item_id, i32),);
+
+query_params_struct!(ItemsParams, (table_id, i32), (item_ids, Vec<i32>),);
+
+// Items are passed as column arrays, all arrays should have same length
+query_params_struct!(
+    InsertItemsParams,
+    (table_id, i32),
+    (menu_item_ids, Vec<i32>),
+    (names, Vec<String>),
+    (comments, Vec<String>),
+    (created_at, Vec<DateTime<Utc>>),
+    (forecast_ready_at, Vec<DateTime<Utc>>),
+);
+
+/// Inserts all items in a single statement, returns created items
+/// Every statement that changes items bumps version of their table, see `Storage::changes_since`
+/// Concurrent bumps of same table conflict, so changes are versioned in order of commit
+pub(super) struct InsertItems;
+
+impl Query for InsertItems {
+    type Params = InsertItemsParams;
+    type Parser = ItemInfoParser;
+    type Output = ItemInfo;
+
+    // ORDER BY ordinality makes item_id sequence values follow order of items in arrays
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        WITH bumped AS (
+            INSERT INTO
+                table_versions
+                (table_id, version)
+            VALUES
+                ($1, 1)
+            ON CONFLICT (table_id) DO UPDATE SET
+                version = table_versions.version + 1
+            RETURNING
+                version
+        )
+        INSERT INTO
+            items
+            (table_id, menu_item_id, name, comment, created_at, forecast_ready_at, version)
+        SELECT
+            $1,
+            new_items.menu_item_id,
+            new_items.name,
+            new_items.comment,
+            new_items.created_at,
+            new_items.forecast_ready_at,
+            bumped.version
+        FROM
+            UNNEST($2::INT[], $3::TEXT[], $4::TEXT[], $5::TIMESTAMPTZ[], $6::TIMESTAMPTZ[])
+                WITH ORDINALITY
+                AS new_items (menu_item_id, name, comment, created_at, forecast_ready_at, idx),
+            bumped
+        ORDER BY
+            new_items.idx
+        RETURNING
+            table_id,
+            item_id,
+            menu_item_id,
+            name,
+            comment,
+            created_at,
+            forecast_ready_at,
+            status,
+            cooking_at,
+            ready_at,
+            served_at
+    ";
+}
+
+#[derive(RowsParser)]
+pub(super) struct RemovedItem {
+    #[rows_parser(native = i32)]
+    pub item_id: ItemId,
+}
+
+/// Returns ids of actually removed items, and keeps them in `removed_items`
+/// Version is bumped only if anything was removed
+pub(super) struct RemoveItems;
+
+impl Query for RemoveItems {
+    type Params = ItemsParams;
+    type Parser = RemovedItemParser;
+    type Output = RemovedItem;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        WITH removed AS (
+            DELETE FROM
+                items
+            WHERE
+                table_id = $1
+                AND
+                item_id = ANY($2)
+            RETURNING
+                item_id
+        ),
+        bumped AS (
+            INSERT INTO
+                table_versions
+                (table_id, version)
+            SELECT
+                $1,
+                1
+            WHERE
+                EXISTS (SELECT FROM removed)
+            ON CONFLICT (table_id) DO UPDATE SET
+                version = table_versions.version + 1
+            RETURNING
+                version
+        ),
+        tombstones AS (
+            INSERT INTO
+                removed_items
+                (table_id, item_id, version, removed_at)
+            SELECT
+                $1,
+                removed.item_id,
+                bumped.version,
+                now()
+            FROM
+                removed,
+                bumped
+        )
+        SELECT
+            item_id
+        FROM
+            removed
+    ";
+}
+
+pub(super) struct ListItems;
+
+impl Query for ListItems {
+    type Params = TableParams;
+    type Parser = ItemInfoShortParser;
+    type Output = ItemInfoShort;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            table_id,
+            item_id,
+            name,
+            status,
+            forecast_ready_at
+        FROM
+            items
+        WHERE
+            table_id = $1
+        ORDER BY
+            item_id
+    ";
+}
+
+query_params_struct!(TableVersionParams, (table_id, i32), (since, i64),);
+
+#[derive(RowsParser)]
+pub(super) struct TableVersionRow {
+    pub version: i64,
+    /// Removals up to this version are forgotten, see `DeleteExpiredTombstones`
+    pub pruned_version: i64,
+}
+
+/// Returns nothing for table without any changes
+pub(super) struct GetTableVersion;
+
+impl Query for GetTableVersion {
+    type Params = TableParams;
+    type Parser = TableVersionRowParser;
+    type Output = TableVersionRow;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            version,
+            pruned_version
+        FROM
+            table_versions
+        WHERE
+            table_id = $1
+    ";
+}
+
+/// Same as `ListItems`, but only items changed after given version
+pub(super) struct ListChangedItems;
+
+impl Query for ListChangedItems {
+    type Params = TableVersionParams;
+    type Parser = ItemInfoShortParser;
+    type Output = ItemInfoShort;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            table_id,
+            item_id,
+            name,
+            status,
+            forecast_ready_at
+        FROM
+            items
+        WHERE
+            table_id = $1
+            AND
+            version > $2
+        ORDER BY
+            item_id
+    ";
+}
+
+/// Items removed after given version
+pub(super) struct ListRemovedItems;
+
+impl Query for ListRemovedItems {
+    type Params = TableVersionParams;
+    type Parser = RemovedItemParser;
+    type Output = RemovedItem;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            item_id
+        FROM
+            removed_items
+        WHERE
+            table_id = $1
+            AND
+            version > $2
+        ORDER BY
+            version,
+            item_id
+    ";
+}
+
+pub(super) struct GetItem;
+
+impl Query for GetItem {
+    type Params = ItemParams;
+    type Parser = ItemInfoParser;
+    type Output = ItemInfo;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            table_id,
+            item_id,
+            menu_item_id,
+            name,
+            comment,
+            created_at,
+            forecast_ready_at,
+            status,
+            cooking_at,
+            ready_at,
+            served_at
+        FROM
+            items
+        WHERE
+            table_id = $1
+            AND
+            item_id = $2
+    ";
+}
+
+pub(super) struct GetItems;
+
+impl Query for GetItems {
+    type Params = ItemsParams;
+    type Parser = ItemInfoParser;
+    type Output = ItemInfo;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            table_id,
+            item_id,
+            menu_item_id,
+            name,
+            comment,
+            created_at,
+            forecast_ready_at,
+            status,
+            cooking_at,
+            ready_at,
+            served_at
+        FROM
+            items
+        WHERE
+            table_id = $1
+            AND
+            item_id = ANY($2)
+        ORDER BY
+            item_id
+    ";
+}
+
+// Pairs of table and item ids, both arrays should have same length
+query_params_struct!(ItemKeysParams, (table_ids, Vec<i32>), (item_ids, Vec<i32>),);
+
+/// Items of any tables, e.g. for a batch of item notifications
+pub(super) struct GetItemsOfTables;
+
+impl Query for GetItemsOfTables {
+    type Params = ItemKeysParams;
+    type Parser = ItemInfoParser;
+    type Output = ItemInfo;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            table_id,
+            item_id,
+            menu_item_id,
+            name,
+            comment,
+            created_at,
+            forecast_ready_at,
+            status,
+            cooking_at,
+            ready_at,
+            served_at
+        FROM
+            items
+        WHERE
+            (table_id, item_id) IN (
+                SELECT
+                    *
+                FROM
+                    UNNEST($1::INT[], $2::INT[])
+            )
+    ";
+}
+
+query_params_struct!(
+    ItemStatusParams,
+    (table_id, i32),
+    (item_id, i32),
+    (status, ItemStatus),
+    (cooking_at, Option<DateTime<Utc>>),
+    (ready_at, Option<DateTime<Utc>>),
+    (served_at, Option<DateTime<Utc>>),
+);
+
+/// Transition should be validated before
+pub(super) struct UpdateItemStatus;
+
+impl Query for UpdateItemStatus {
+    type Params = ItemStatusParams;
+    type Parser = NoRows;
+    type Output = ();
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        WITH bumped AS (
+            INSERT INTO
+                table_versions
+                (table_id, version)
+            VALUES
+                ($1, 1)
+            ON CONFLICT (table_id) DO UPDATE SET
+                version = table_versions.version + 1
+            RETURNING
+                version
+        )
+        UPDATE
+            items
+        SET
+            status = $3,
+            cooking_at = $4,
+            ready_at = $5,
+            served_at = $6,
+            version = bumped.version
+        FROM
+            bumped
+        WHERE
+            table_id = $1
+            AND
+            item_id = $2
+    ";
+}
+
+query_params_struct!(
+    IdempotencyKeyParams,
+    (table_id, i32),
+    (key, String),
+    (expired_before, DateTime<Utc>),
+);
+
+#[derive(RowsParser)]
+pub(super) struct IdempotentItemIds {
+    pub item_ids: Vec<i32>,
+}
+
+/// Returns ids of items created with this key, if key was used within retention window
+pub(super) struct GetIdempotencyKey;
+
+impl Query for GetIdempotencyKey {
+    type Params = IdempotencyKeyParams;
+    type Parser = IdempotentItemIdsParser;
+    type Output = IdempotentItemIds;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            item_ids
+        FROM
+            idempotency_keys
+        WHERE
+            table_id = $1
+            AND
+            key = $2
+            AND
+            created_at >= $3
+    ";
+}
+
+query_params_struct!(
+    ExpiredIdempotencyKeysParams,
+    (expired_before, DateTime<Utc>),
+);
+
+#[derive(RowsParser)]
+pub(super) struct DeletedCount {
+    pub deleted: i64,
+}
+
+/// Keys that can not be replayed anymore, for all tables
+/// This touches whole table, so it is not a part of `add_items`, to avoid conflicts with every order
+pub(super) struct DeleteExpiredIdempotencyKeys;
+
+impl Query for DeleteExpiredIdempotencyKeys {
+    type Params = ExpiredIdempotencyKeysParams;
+    type Parser = DeletedCountParser;
+    type Output = DeletedCount;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        WITH deleted AS (
+            DELETE FROM
+                idempotency_keys
+            WHERE
+                created_at < $1
+            RETURNING
+                1
+        )
+        SELECT
+            COUNT(*) AS deleted
+        FROM
+            deleted
+    ";
+}
+
+query_params_struct!(ExpiredTombstonesParams, (expired_before, DateTime<Utc>),);
+
+/// Removed items that `changes_since` should not report anymore, for all tables
+/// Tables remember latest version of deleted ones, so clients with older cursors are reset
+pub(super) struct DeleteExpiredTombstones;
+
+impl Query for DeleteExpiredTombstones {
+    type Params = ExpiredTombstonesParams;
+    type Parser = DeletedCountParser;
+    type Output = DeletedCount;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        WITH deleted AS (
+            DELETE FROM
+                removed_items
+            WHERE
+                removed_at < $1
+            RETURNING
+                table_id,
+                version
+        ),
+        pruned AS (
+            UPDATE
+                table_versions
+            SET
+                pruned_version = GREATEST(table_versions.pruned_version, latest.version)
+            FROM
+                (
+                    SELECT
+                        table_id,
+                        MAX(version) AS version
+                    FROM
+                        deleted
+                    GROUP BY
+                        table_id
+                ) AS latest
+            WHERE
+                table_versions.table_id = latest.table_id
+        )
+        SELECT
+            COUNT(*) AS deleted
+        FROM
+            deleted
+    ";
+}
+
+query_params_struct!(
+    ClaimIdempotencyKeyParams,
+    (table_id, i32),
+    (key, String),
+    (created_at, DateTime<Utc>),
+    (item_ids, Vec<i32>),
+    (expired_before, DateTime<Utc>),
+);
+
+/// Stores key unless it was already used within retention window, returns nothing in that case
+/// Unique index is the arbiter here: checking for key with SELECT first would put SIREAD lock
+/// on whole `idempotency_keys`, and make every pair of concurrent orders conflict
+pub(super) struct ClaimIdempotencyKey;
+
+impl Query for ClaimIdempotencyKey {
+    type Params = ClaimIdempotencyKeyParams;
+    type Parser = IdempotentItemIdsParser;
+    type Output = IdempotentItemIds;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        INSERT INTO
+            idempotency_keys
+            (table_id, key, created_at, item_ids)
+        VALUES
+            ($1, $2, $3, $4)
+        ON CONFLICT (table_id, key) DO UPDATE SET
+            created_at = excluded.created_at,
+            item_ids = excluded.item_ids
+        WHERE
+            idempotency_keys.created_at < $5
+        RETURNING
+            item_ids
+    ";
+}
+
+query_params_struct!(
+    InsertMenuItemsParams,
+    (names, Vec<String>),
+    (categories, Vec<String>),
+    (available, Vec<bool>),
+);
+
+/// Same approach as `InsertItems`
+pub(super) struct InsertMenuItems;
+
+impl Query for InsertMenuItems {
+    type Params = InsertMenuItemsParams;
+    type Parser = MenuItemParser;
+    type Output = MenuItem;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        INSERT INTO
+            menu_items
+            (name, category, available)
+        SELECT
+            new_items.name,
+            new_items.category,
+            new_items.available
+        FROM
+            UNNEST($1::TEXT[], $2::TEXT[], $3::BOOL[])
+                WITH ORDINALITY
+                AS new_items (name, category, available, idx)
+        ORDER BY
+            new_items.idx
+        RETURNING
+            menu_item_id,
+            name,
+            category,
+            available
+    ";
+}
+
+query_params_struct!(MenuItemsParams, (menu_item_ids, Vec<i32>),);
+
+query_params_struct!(
+    MenuItemAvailableParams,
+    (menu_item_id, i32),
+    (available, bool),
+);
+
+pub(super) struct ListMenuItems;
+
+impl Query for ListMenuItems {
+    type Params = ();
+    type Parser = MenuItemParser;
+    type Output = MenuItem;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            menu_item_id,
+            name,
+            category,
+            available
+        FROM
+            menu_items
+        ORDER BY
+            menu_item_id
+    ";
+}
+
+pub(super) struct GetMenuItems;
+
+impl Query for GetMenuItems {
+    type Params = MenuItemsParams;
+    type Parser = MenuItemParser;
+    type Output = MenuItem;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            menu_item_id,
+            name,
+            category,
+            available
+        FROM
+            menu_items
+        WHERE
+            menu_item_id = ANY($1)
+        ORDER BY
+            menu_item_id
+    ";
+}
+
+pub(super) struct SetMenuItemAvailable;
+
+impl Query for SetMenuItemAvailable {
+    type Params = MenuItemAvailableParams;
+    type Parser = MenuItemParser;
+    type Output = MenuItem;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        UPDATE
+            menu_items
+        SET
+            available = $2
+        WHERE
+            menu_item_id = $1
+        RETURNING
+            menu_item_id,
+            name,
+            category,
+            available
+    ";
+}
+
+query_params_struct!(SinceParams, (since, DateTime<Utc>),);
+
+#[derive(RowsParser)]
+pub(super) struct PrepTimeRow {
+    pub menu_item_id: MenuItemId,
+    pub completed: i64,
+    /// There's no FromSql for chrono::Duration, so interval is passed as milliseconds
+    pub average_ms: i64,
+}
+
+/// Cooking times of items ordered since given time, per menu item
+pub(super) struct GetPrepTimes;
+
+impl Query for GetPrepTimes {
+    type Params = SinceParams;
+    type Parser = PrepTimeRowParser;
+    type Output = PrepTimeRow;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            menu_item_id,
+            COUNT(*) AS completed,
+            (EXTRACT(EPOCH FROM AVG(ready_at - cooking_at)) * 1000)::INT8 AS average_ms
+        FROM
+            items
+        WHERE
+            created_at >= $1
+            AND
+            menu_item_id IS NOT NULL
+            AND
+            ready_at IS NOT NULL
+        GROUP BY
+            menu_item_id
+        ORDER BY
+            menu_item_id
+    ";
+}
+
+#[derive(RowsParser)]
+pub(super) struct QueuedItems {
+    pub queued: i64,
+}
+
+/// Items ordered since given time, that are not ready yet
+pub(super) struct CountQueuedItems;
+
+impl Query for CountQueuedItems {
+    type Params = SinceParams;
+    type Parser = QueuedItemsParser;
+    type Output = QueuedItems;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            COUNT(*) AS queued
+        FROM
+            items
+        WHERE
+            created_at >= $1
+            AND
+            status IN ('ordered', 'cooking')
+    ";
+}
+
+/// Items that are not ready yet, for all tables, in order kitchen takes them
+pub(super) struct ListOutstandingItems;
+
+impl Query for ListOutstandingItems {
+    type Params = SinceParams;
+    type Parser = ItemInfoParser;
+    type Output = ItemInfo;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            table_id,
+            item_id,
+            menu_item_id,
+            name,
+            comment,
+            created_at,
+            forecast_ready_at,
+            status,
+            cooking_at,
+            ready_at,
+            served_at
+        FROM
+            items
+        WHERE
+            created_at >= $1
+            AND
+            status IN ('ordered', 'cooking')
+        ORDER BY
+            item_id
+    ";
+}
+
+// Same as for InsertItems, all arrays should have same length
+query_params_struct!(
+    ForecastsParams,
+    (table_ids, Vec<i32>),
+    (item_ids, Vec<i32>),
+    (forecast_ready_at, Vec<DateTime<Utc>>),
+);
+
+#[derive(RowsParser)]
+pub(super) struct UpdatedForecast {
+    pub table_id: i32,
+    pub item_id: i32,
+}
+
+/// Items that got ready meanwhile keep their forecast, returns updated ones
+/// Version of each table is bumped once, for all of its updated items
+pub(super) struct UpdateForecasts;
+
+impl Query for UpdateForecasts {
+    type Params = ForecastsParams;
+    type Parser = UpdatedForecastParser;
+    type Output = UpdatedForecast;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        WITH forecasts AS (
+            SELECT
+                items.table_id,
+                items.item_id,
+                new_forecasts.forecast_ready_at
+            FROM
+                items
+                JOIN
+                UNNEST($1::INT[], $2::INT[], $3::TIMESTAMPTZ[])
+                    AS new_forecasts (table_id, item_id, forecast_ready_at)
+                USING (table_id, item_id)
+            WHERE
+                items.status IN ('ordered', 'cooking')
+        ),
+        bumped AS (
+            INSERT INTO
+                table_versions
+                (table_id, version)
+            SELECT DISTINCT
+                table_id,
+                1
+            FROM
+                forecasts
+            ON CONFLICT (table_id) DO UPDATE SET
+                version = table_versions.version + 1
+            RETURNING
+                table_id,
+                version
+        )
+        UPDATE
+            items
+        SET
+            forecast_ready_at = forecasts.forecast_ready_at,
+            version = bumped.version
+        FROM
+            forecasts
+            JOIN
+            bumped USING (table_id)
+        WHERE
+            items.table_id = forecasts.table_id
+            AND
+            items.item_id = forecasts.item_id
+        RETURNING
+            items.table_id,
+            items.item_id
+    ";
+}
+
+query_params_struct!(NotifyParams, (channel, String), (payloads, Vec<String>),);
+
+/// Sends notification for each payload, outer SELECT is only to return no columns
+pub(super) struct NotifyItemEvents;
+
+impl Query for NotifyItemEvents {
+    type Params = NotifyParams;
+    type Parser = NoRows;
+    type Output = ();
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT FROM (
+            SELECT
+                pg_notify($1, payload)
+            FROM
+                UNNEST($2::TEXT[]) AS payload
+        ) AS notified
+    ";
+}
+
+query_params_struct!(
+    InsertTablesParams,
+    (names, Vec<String>),
+    (seats, Vec<i32>),
+    (sections, Vec<String>),
+);
+
+/// Same approach as `InsertItems`
+pub(super) struct InsertTables;
+
+impl Query for InsertTables {
+    type Params = InsertTablesParams;
+    type Parser = TableParser;
+    type Output = Table;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        INSERT INTO
+            restaurant_tables
+            (name, seats, section, active)
+        SELECT
+            new_tables.name,
+            new_tables.seats,
+            new_tables.section,
+            TRUE
+        FROM
+            UNNEST($1::TEXT[], $2::INT[], $3::TEXT[])
+                WITH ORDINALITY
+                AS new_tables (name, seats, section, idx)
+        ORDER BY
+            new_tables.idx
+        RETURNING
+            table_id,
+            name,
+            seats,
+            section,
+            active
+    ";
+}
+
+query_params_struct!(TableActiveParams, (table_id, i32), (active, bool),);
+
+pub(super) struct ListTables;
+
+impl Query for ListTables {
+    type Params = ();
+    type Parser = TableParser;
+    type Output = Table;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            table_id,
+            name,
+            seats,
+            section,
+            active
+        FROM
+            restaurant_tables
+        ORDER BY
+            table_id
+    ";
+}
+
+pub(super) struct GetTable;
+
+impl Query for GetTable {
+    type Params = TableParams;
+    type Parser = TableParser;
+    type Output = Table;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        SELECT
+            table_id,
+            name,
+            seats,
+            section,
+            active
+        FROM
+            restaurant_tables
+        WHERE
+            table_id = $1
+    ";
+}
+
+pub(super) struct SetTableActive;
+
+impl Query for SetTableActive {
+    type Params = TableActiveParams;
+    type Parser = TableParser;
+    type Output = Table;
+
+    // language=PostgreSQL
+    const SQL: &'static str = "
+        UPDATE
+            restaurant_tables
+        SET
+            active = $2
+        WHERE
+            table_id = $1
+        RETURNING
+            table_id,
+            name,
+            seats,
+            section,
+            active
+    ";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::pg::migrations::migrate_up;
+    use crate::storage::pg::tests::create_test_database;
+
+    // This test requires external PostgreSQL instance, so it is ignored by default
+    #[tokio::test]
+    #[ignore]
+    async fn test_preflight() {
+        let pool = create_test_database().await;
+        migrate_up(&pool).await.unwrap();
+
+        let db = pool.get().await.unwrap();
+        preflight_all(&db).await.unwrap();
+
+        db.batch_execute(
+            // language=PostgreSQL
+            "ALTER TABLE items ALTER COLUMN created_at TYPE TIMESTAMP",
+        )
+        .await
+        .unwrap();
+        // Parameters of InsertItems are pinned with casts, so mismatch shows up in returned columns
+        let err = preflight_all(&db).await.unwrap_err();
+        assert!(matches!(
+            err,
+            PostgresStorageError::Preflight { ref cause, .. }
+            if matches!(**cause, PostgresStorageError::IncompatibleColumnType { column: "created_at", .. })
+        ));
+    }
+}
@@ -0,0 +1,141 @@
+use async_trait::async_trait;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::{Client, Column, Row, Transaction};
+
+use super::rows::RowsParser;
+use super::PostgresStorageError;
+
+/// Query parameters, encoded in order of placeholders: first field is $1, second is $2, and so on
+/// Could be implemented manually, or via `query_params_struct` macro
+pub(super) trait QueryParams {
+    fn to_params(&self) -> Vec<&(dyn ToSql + Sync)>;
+    /// Check parameter types inferred by DB for prepared statement against Rust types
+    fn check_types(types: &[Type]) -> Result<(), PostgresStorageError>;
+}
+
+/// Macro to generate struct with given fields, and implementation of QueryParams for it
+/// Fields are passed to query in order of declaration
+macro_rules! query_params_struct {
+    ($ty: ident, $(($field: ident, $field_ty: ty),)+) => (
+        pub(super) struct $ty {
+            $(pub $field: $field_ty,)+
+        }
+        impl $crate::storage::pg::query::QueryParams for $ty {
+            fn to_params(&self) -> Vec<&(dyn tokio_postgres::types::ToSql + Sync)> {
+                vec![$(&self.$field,)+]
+            }
+            fn check_types(
+                types: &[tokio_postgres::types::Type],
+            ) -> Result<(), $crate::storage::pg::PostgresStorageError> {
+                let accepts: &[fn(&tokio_postgres::types::Type) -> bool] =
+                    &[$(<$field_ty as tokio_postgres::types::ToSql>::accepts,)+];
+                $crate::storage::pg::query::check_param_types(accepts, types)
+            }
+        }
+    );
+}
+
+pub(super) use query_params_struct;
+
+/// For queries without parameters
+impl QueryParams for () {
+    fn to_params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        vec![]
+    }
+
+    fn check_types(types: &[Type]) -> Result<(), PostgresStorageError> {
+        check_param_types(&[], types)
+    }
+}
+
+pub(super) fn check_param_types(
+    accepts: &[fn(&Type) -> bool],
+    types: &[Type],
+) -> Result<(), PostgresStorageError> {
+    if accepts.len() != types.len() {
+        return Err(PostgresStorageError::ParamCountMismatch {
+            expected: accepts.len(),
+            found: types.len(),
+        });
+    }
+    for (idx, (accepts, ty)) in accepts.iter().zip(types).enumerate() {
+        if !accepts(ty) {
+            return Err(PostgresStorageError::IncompatibleParamType {
+                // Same numbering as placeholders in query
+                index: idx + 1,
+                found: ty.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Parser for statements that should not return any rows, like INSERT without RETURNING
+pub(super) struct NoRows;
+
+impl RowsParser for NoRows {
+    type Output = ();
+
+    fn prepare(_columns: &[Column]) -> Result<Self, PostgresStorageError> {
+        Ok(NoRows)
+    }
+
+    fn parse(&self, _row: Row) -> Result<Self::Output, PostgresStorageError> {
+        Ok(())
+    }
+
+    fn verify(columns: &[Column]) -> Result<(), PostgresStorageError> {
+        if columns.is_empty() {
+            Ok(())
+        } else {
+            Err(PostgresStorageError::UnexpectedColumns(columns.len()))
+        }
+    }
+}
+
+/// Query text together with its parameters and result parser, kind of a "stored procedure"
+/// on the client side. Given parameters it can be applied to any transaction.
+#[async_trait]
+pub(super) trait Query {
+    type Params: QueryParams + Sync;
+    type Parser: RowsParser<Output = Self::Output>;
+    type Output: Send;
+
+    const SQL: &'static str;
+
+    async fn query(
+        txn: &Transaction<'_>,
+        params: &Self::Params,
+    ) -> Result<Vec<Self::Output>, PostgresStorageError> {
+        let rows = txn.query(Self::SQL, &params.to_params()).await?;
+        Self::Parser::parse_many(rows)
+    }
+
+    async fn query_opt(
+        txn: &Transaction<'_>,
+        params: &Self::Params,
+    ) -> Result<Option<Self::Output>, PostgresStorageError> {
+        let row = txn.query_opt(Self::SQL, &params.to_params()).await?;
+        row.map(Self::Parser::parse_one).transpose()
+    }
+
+    /// Prepare statement without executing it, and check that its parameters and result columns
+    /// match Rust types
+    async fn preflight(db: &Client) -> Result<(), PostgresStorageError> {
+        let statement = db.prepare(Self::SQL).await?;
+        Self::Params::check_types(statement.params())
+            .and_then(|_| Self::Parser::verify(statement.columns()))
+            .map_err(|cause| PostgresStorageError::Preflight {
+                query: std::any::type_name::<Self>(),
+                cause: Box::new(cause),
+            })
+    }
+
+    /// Returns count of affected rows
+    async fn execute(
+        txn: &Transaction<'_>,
+        params: &Self::Params,
+    ) -> Result<u64, PostgresStorageError> {
+        Ok(txn.execute(Self::SQL, &params.to_params()).await?)
+    }
+}
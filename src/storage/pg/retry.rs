@@ -0,0 +1,230 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use rand::Rng;
+use tokio_postgres::error::SqlState;
+use tracing::{info, warn};
+
+use super::PostgresStorageError;
+
+/// How to retry transactions that failed because of concurrent transactions
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total attempts, including first one, 1 disables retries
+    pub max_attempts: u32,
+    /// Delay before first retry, doubled for every next one
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(5),
+            max_delay: Duration::from_millis(200),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Full jitter: uniformly random delay up to exponential backoff,
+    /// so conflicting transactions would not retry in lockstep and conflict again
+    fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        rand::thread_rng().gen_range(Duration::ZERO..=backoff)
+    }
+}
+
+/// Counters for all transactions of a single storage, shared with whoever wants to report them
+#[derive(Debug, Default)]
+pub struct RetryStats {
+    transactions: AtomicU64,
+    retries: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RetryStatsSnapshot {
+    pub transactions: u64,
+    /// Attempts beyond first one
+    pub retries: u64,
+    /// Transactions that failed with retryable error on last attempt
+    pub exhausted: u64,
+}
+
+impl RetryStats {
+    pub fn snapshot(&self) -> RetryStatsSnapshot {
+        RetryStatsSnapshot {
+            transactions: self.transactions.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            exhausted: self.exhausted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Errors `with_retry` could tell transient ones of, generic so loop could be tested without DB
+pub(super) trait Retryable: Display {
+    /// Errors caused by concurrent transactions, that could succeed when transaction is repeated
+    fn is_retryable(&self) -> bool;
+}
+
+impl Retryable for PostgresStorageError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            PostgresStorageError::DbError(e) => matches!(
+                e.code(),
+                Some(&SqlState::T_R_SERIALIZATION_FAILURE) | Some(&SqlState::T_R_DEADLOCK_DETECTED)
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Run `attempt` until it succeeds, fails with permanent error, or policy runs out of attempts
+/// Every call to `attempt` should run a whole transaction from the start
+pub(super) async fn with_retry<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    stats: &RetryStats,
+    op: &'static str,
+    mut attempt: F,
+) -> Result<T, E>
+where
+    E: Retryable,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    stats.transactions.fetch_add(1, Ordering::Relaxed);
+
+    let mut attempts = 1;
+    loop {
+        match attempt().await {
+            Ok(result) => {
+                if attempts > 1 {
+                    info!(op, attempts, "Transaction succeeded after retries");
+                }
+                return Ok(result);
+            }
+            Err(e) if e.is_retryable() => {
+                if attempts >= policy.max_attempts {
+                    stats.exhausted.fetch_add(1, Ordering::Relaxed);
+                    warn!(op, attempts, error = %e, "Giving up retrying transaction");
+                    return Err(e);
+                }
+                let delay = policy.delay(attempts - 1);
+                warn!(op, attempts, error = %e, ?delay, "Retrying transaction");
+                stats.retries.fetch_add(1, Ordering::Relaxed);
+                attempts += 1;
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+
+    #[derive(Debug, derive_more::Display, Eq, PartialEq)]
+    enum TestError {
+        Conflict,
+        Permanent,
+    }
+
+    impl Retryable for TestError {
+        fn is_retryable(&self) -> bool {
+            *self == TestError::Conflict
+        }
+    }
+
+    fn test_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn test_delay_is_capped() {
+        let policy = RetryPolicy::default();
+        for retry in 0..64 {
+            assert!(policy.delay(retry) <= policy.max_delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retried_until_success() {
+        let stats = RetryStats::default();
+        let calls = Cell::new(0);
+        let result = with_retry(&test_policy(5), &stats, "test", || {
+            calls.set(calls.get() + 1);
+            let result = if calls.get() < 3 {
+                Err(TestError::Conflict)
+            } else {
+                Ok(calls.get())
+            };
+            async move { result }
+        })
+        .await;
+
+        assert_eq!(result, Ok(3));
+        assert_eq!(
+            stats.snapshot(),
+            RetryStatsSnapshot {
+                transactions: 1,
+                retries: 2,
+                exhausted: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_permanent_error_is_not_retried() {
+        let stats = RetryStats::default();
+        let calls = Cell::new(0);
+        let result: Result<(), _> = with_retry(&test_policy(5), &stats, "test", || {
+            calls.set(calls.get() + 1);
+            async { Err(TestError::Permanent) }
+        })
+        .await;
+
+        assert_eq!(result, Err(TestError::Permanent));
+        assert_eq!(calls.get(), 1);
+        assert_eq!(stats.snapshot().retries, 0);
+        assert_eq!(stats.snapshot().exhausted, 0);
+    }
+
+    #[tokio::test]
+    async fn test_attempts_are_limited() {
+        for max_attempts in [1, 3] {
+            let stats = RetryStats::default();
+            let calls = Cell::new(0);
+            let result: Result<(), _> =
+                with_retry(&test_policy(max_attempts), &stats, "test", || {
+                    calls.set(calls.get() + 1);
+                    async { Err(TestError::Conflict) }
+                })
+                .await;
+
+            assert_eq!(result, Err(TestError::Conflict));
+            assert_eq!(calls.get(), max_attempts);
+            assert_eq!(
+                stats.snapshot(),
+                RetryStatsSnapshot {
+                    transactions: 1,
+                    retries: u64::from(max_attempts - 1),
+                    exhausted: 1,
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use tokio_postgres::types::{FromSql, Type};
+use tokio_postgres::{Column, Row};
+
+use super::PostgresStorageError;
+
+/// Generic interface to parse result sets from DB to Rust types
+/// Could be implemented manually, or via `#[derive(RowsParser)]` from `rows-parser-derive`
+pub(crate) trait RowsParser: Sized {
+    type Output;
+
+    fn prepare(columns: &[Column]) -> Result<Self, PostgresStorageError>;
+    fn parse(&self, row: Row) -> Result<Self::Output, PostgresStorageError>;
+    /// Check that result set with such columns can be parsed, without having any rows at hand
+    fn verify(columns: &[Column]) -> Result<(), PostgresStorageError> {
+        Self::prepare(columns).map(|_| ())
+    }
+    fn parse_one(row: Row) -> Result<Self::Output, PostgresStorageError> {
+        let parser = Self::prepare(row.columns())?;
+        parser.parse(row)
+    }
+    fn parse_many(rows: Vec<Row>) -> Result<Vec<Self::Output>, PostgresStorageError> {
+        if rows.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let parser = Self::prepare(rows[0].columns())?;
+        rows.into_iter().map(|row| parser.parse(row)).collect()
+    }
+}
+
+// Helpers below are used by code generated with derive macro
+
+pub(crate) fn build_column_map<const N: usize>(
+    reference_columns: &[&'static str; N],
+    input_columns: &[Column],
+) -> Result<[usize; N], PostgresStorageError> {
+    // array::try_map is unstable
+    let mut result: [usize; N] = [0; N];
+    for (reference_idx, reference_column) in reference_columns.iter().enumerate() {
+        let input_idx = input_columns
+            .iter()
+            .position(|input_column| input_column.name() == *reference_column)
+            // We could use Error::column from tokio_postgres here, but it's private
+            .ok_or_else(|| PostgresStorageError::ColumnNotFound(reference_column))?;
+        result[reference_idx] = input_idx;
+    }
+    Ok(result)
+}
+
+pub(crate) fn try_get_field<T: for<'a> FromSql<'a>>(
+    row: &Row,
+    name: usize,
+) -> Result<T, PostgresStorageError> {
+    Ok(row.try_get(name)?)
+}
+
+/// Helper to get FromSql of struct field type, without naming that type
+pub(crate) fn field_accepts<O, T: for<'a> FromSql<'a>>(_field: fn(&O) -> &T, ty: &Type) -> bool {
+    T::accepts(ty)
+}
+
+pub(crate) fn check_column_type(
+    column: &'static str,
+    ty: &Type,
+    accepts: bool,
+) -> Result<(), PostgresStorageError> {
+    if accepts {
+        Ok(())
+    } else {
+        Err(PostgresStorageError::IncompatibleColumnType {
+            column,
+            found: ty.clone(),
+        })
+    }
+}
@@ -0,0 +1,177 @@
+use deadpool_postgres::Pool;
+
+use super::migrations::latest_version;
+use super::PostgresStorageError;
+
+/// Columns that queries in `PostgresStorage` rely on, as (table, column, type name in `pg_type`)
+/// Should be updated together with migrations that change those tables
+const EXPECTED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("items", "item_id", "int4"),
+    ("items", "table_id", "int4"),
+    ("items", "name", "text"),
+    ("items", "comment", "text"),
+    ("items", "created_at", "timestamptz"),
+    ("items", "forecast_ready_at", "timestamptz"),
+    ("items", "status", "text"),
+    ("items", "cooking_at", "timestamptz"),
+    ("items", "ready_at", "timestamptz"),
+    ("items", "served_at", "timestamptz"),
+    ("items", "menu_item_id", "int4"),
+    ("items", "version", "int8"),
+    ("idempotency_keys", "table_id", "int4"),
+    ("idempotency_keys", "key", "text"),
+    ("idempotency_keys", "created_at", "timestamptz"),
+    ("idempotency_keys", "item_ids", "_int4"),
+    ("menu_items", "menu_item_id", "int4"),
+    ("menu_items", "name", "text"),
+    ("menu_items", "category", "text"),
+    ("menu_items", "available", "bool"),
+    ("restaurant_tables", "table_id", "int4"),
+    ("restaurant_tables", "name", "text"),
+    ("restaurant_tables", "seats", "int4"),
+    ("restaurant_tables", "section", "text"),
+    ("restaurant_tables", "active", "bool"),
+    ("table_versions", "table_id", "int4"),
+    ("table_versions", "version", "int8"),
+    ("table_versions", "pruned_version", "int8"),
+    ("removed_items", "table_id", "int4"),
+    ("removed_items", "item_id", "int4"),
+    ("removed_items", "version", "int8"),
+    ("removed_items", "removed_at", "timestamptz"),
+];
+
+/// Check that database was migrated at least to the version this build expects,
+/// and that columns used by queries have expected types
+/// Newer schema is accepted, as migrations are additive and run before rollout, so instances of
+/// previous version keep working, and could restart, until they are replaced
+pub async fn check_schema(pool: &Pool) -> Result<(), PostgresStorageError> {
+    let db = pool.get().await?;
+
+    let migrated: bool = db
+        .query_one(
+            // language=PostgreSQL
+            "SELECT to_regclass('schema_migrations') IS NOT NULL",
+            &[],
+        )
+        .await?
+        .try_get(0)?;
+    let found: Option<i32> = if migrated {
+        db.query_one(
+            // language=PostgreSQL
+            "SELECT MAX(version) FROM schema_migrations",
+            &[],
+        )
+        .await?
+        .try_get(0)?
+    } else {
+        None
+    };
+    let expected = latest_version();
+    if found.is_none_or(|found| found < expected) {
+        return Err(PostgresStorageError::IncompatibleSchemaVersion { expected, found });
+    }
+
+    let rows = db
+        .query(
+            // language=PostgreSQL
+            "
+                SELECT
+                    table_name::text,
+                    column_name::text,
+                    udt_name::text
+                FROM
+                    information_schema.columns
+                WHERE
+                    table_schema = current_schema()
+            ",
+            &[],
+        )
+        .await?;
+    let columns = rows
+        .iter()
+        .map(|row| -> Result<_, PostgresStorageError> {
+            Ok((
+                row.try_get::<_, String>(0)?,
+                row.try_get::<_, String>(1)?,
+                row.try_get::<_, String>(2)?,
+            ))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for &(table, column, expected) in EXPECTED_COLUMNS {
+        let found = columns
+            .iter()
+            .find(|(t, c, _)| t == table && c == column)
+            .map(|(_, _, ty)| ty.clone());
+        if found.as_deref() != Some(expected) {
+            return Err(PostgresStorageError::IncompatibleColumn {
+                table,
+                column,
+                expected,
+                found,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::pg::migrations::migrate_up;
+    use crate::storage::pg::tests::create_test_database;
+
+    // This test requires external PostgreSQL instance, so it is ignored by default
+    #[tokio::test]
+    #[ignore]
+    async fn test_check_schema() {
+        let pool = create_test_database().await;
+
+        assert!(matches!(
+            check_schema(&pool).await,
+            Err(PostgresStorageError::IncompatibleSchemaVersion { found: None, .. })
+        ));
+
+        migrate_up(&pool).await.unwrap();
+        check_schema(&pool).await.unwrap();
+
+        // Like newer version has migrated database during rollout
+        pool.get()
+            .await
+            .unwrap()
+            .execute(
+                // language=PostgreSQL
+                "
+                    INSERT INTO
+                        schema_migrations
+                        (version, name, applied_at)
+                    VALUES
+                        ($1, 'from_the_future', now())
+                ",
+                &[&(latest_version() + 1)],
+            )
+            .await
+            .unwrap();
+        check_schema(&pool).await.unwrap();
+
+        pool.get()
+            .await
+            .unwrap()
+            .batch_execute(
+                // language=PostgreSQL
+                "ALTER TABLE items ALTER COLUMN comment TYPE VARCHAR(100)",
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            check_schema(&pool).await,
+            Err(PostgresStorageError::IncompatibleColumn {
+                table: "items",
+                column: "comment",
+                ..
+            })
+        ));
+    }
+}
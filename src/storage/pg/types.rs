@@ -0,0 +1,45 @@
+//! Mapping of model types to PostgreSQL types
+
+use std::error::Error;
+
+use tokio_postgres::types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+
+use crate::storage::model::{ItemStatus, MenuItemId};
+
+type BoxError = Box<dyn Error + Sync + Send>;
+
+/// Stored as TEXT, with CHECK constraint in schema
+impl ToSql for ItemStatus {
+    fn to_sql(&self, ty: &Type, out: &mut bytes::BytesMut) -> Result<IsNull, BoxError> {
+        self.as_str().to_sql(ty, out)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&str as ToSql>::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for ItemStatus {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, BoxError> {
+        let s = <&str as FromSql>::from_sql(ty, raw)?;
+        ItemStatus::parse(s).ok_or_else(|| format!("unknown item status `{s}`").into())
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&str as FromSql>::accepts(ty)
+    }
+}
+
+/// Allows reading nullable `items.menu_item_id` as `Option<MenuItemId>`, `native` attribute
+/// of `RowsParser` does not handle that
+impl<'a> FromSql<'a> for MenuItemId {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, BoxError> {
+        i32::from_sql(ty, raw).map(MenuItemId)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <i32 as FromSql>::accepts(ty)
+    }
+}
@@ -1,16 +1,22 @@
 use std::future::Future;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use tokio::sync::broadcast;
 
 use super::model::*;
 
 const TEST_TABLE_ID: TableId = TableId(1);
+// Storage does not validate menu item ids, so items could refer to anything
+const TEST_MENU_ITEM_ID: MenuItemId = MenuItemId(1);
+const OTHER_TABLE_ID: TableId = TableId(2);
 const CREATED_AT: DateTime<Utc> = DateTime::<Utc>::UNIX_EPOCH;
 const FORECAST_READY_AT: DateTime<Utc> = DateTime::<Utc>::UNIX_EPOCH;
 
 fn test_new_item() -> NewItem {
     NewItem {
+        menu_item_id: TEST_MENU_ITEM_ID,
         name: "test new item".into(),
         comment: "test new item comment".into(),
         created_at: CREATED_AT,
@@ -20,6 +26,7 @@ fn test_new_item() -> NewItem {
 
 fn test_new_item_2() -> NewItem {
     NewItem {
+        menu_item_id: TEST_MENU_ITEM_ID,
         name: "test other item".into(),
         comment: "test other item comment".into(),
         created_at: CREATED_AT,
@@ -52,12 +59,29 @@ where
     run_test(&builder, initially_empty)?;
     run_test(&builder, add_single)?;
     run_test(&builder, add_multiple)?;
+    run_test(&builder, add_many_ordered)?;
+    run_test(&builder, add_empty)?;
     run_test(&builder, list_twice)?;
     run_test(&builder, get_twice)?;
     run_test(&builder, add_remove_single)?;
     run_test(&builder, add_remove_multiple)?;
     run_test(&builder, remove_nonexistent)?;
     run_test(&builder, remove_mixed)?;
+    run_test(&builder, idempotent_replay)?;
+    run_test(&builder, idempotency_key_per_table)?;
+    run_test(&builder, advance_lifecycle)?;
+    run_test(&builder, advance_illegal)?;
+    run_test(&builder, advance_nonexistent)?;
+    run_test(&builder, menu_add_list)?;
+    run_test(&builder, menu_get_some)?;
+    run_test(&builder, menu_set_available)?;
+    run_test(&builder, kitchen_stats)?;
+    run_test(&builder, outstanding_forecasts)?;
+    run_test(&builder, tables_add_list)?;
+    run_test(&builder, tables_get)?;
+    run_test(&builder, tables_set_active)?;
+    run_test(&builder, item_events)?;
+    run_test(&builder, item_changes)?;
 
     Ok(())
 }
@@ -77,7 +101,8 @@ where
     assert!(s.list_items(TEST_TABLE_ID).await?.is_empty());
 
     let item = test_new_item();
-    s.add_items(TEST_TABLE_ID, [item.clone()].into_iter())
+    let added = s
+        .add_items(TEST_TABLE_ID, None, [item.clone()].into_iter())
         .await?;
 
     let items = s.list_items(TEST_TABLE_ID).await?;
@@ -92,16 +117,18 @@ where
     ));
 
     let roundtrip_item = s.get_item(TEST_TABLE_ID, items[0].item_id.clone()).await?;
+    assert_eq!(roundtrip_item.as_ref(), added.first());
     assert!(matches!(
         roundtrip_item,
         Some(ItemInfo {
             table_id: TEST_TABLE_ID,
+            menu_item_id: Some(ref menu_item_id),
             ref name,
             ref created_at,
             ref forecast_ready_at,
             ..
         })
-        if name == &item.name && created_at == &item.created_at && forecast_ready_at == &item.forecast_ready_at
+        if menu_item_id == &item.menu_item_id && name == &item.name && created_at == &item.created_at && forecast_ready_at == &item.forecast_ready_at
     ));
 
     Ok(())
@@ -116,8 +143,12 @@ where
     let item = test_new_item();
     let item2 = test_new_item_2();
 
-    s.add_items(TEST_TABLE_ID, [item.clone(), item2.clone()].into_iter())
-        .await?;
+    s.add_items(
+        TEST_TABLE_ID,
+        None,
+        [item.clone(), item2.clone()].into_iter(),
+    )
+    .await?;
 
     let items = s.list_items(TEST_TABLE_ID).await?;
     assert!(matches!(
@@ -153,6 +184,49 @@ where
     Ok(())
 }
 
+async fn add_many_ordered<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    assert!(s.list_items(TEST_TABLE_ID).await?.is_empty());
+
+    let new_items = (0..20)
+        .map(|idx| NewItem {
+            name: format!("test item {idx}"),
+            ..test_new_item()
+        })
+        .collect::<Vec<_>>();
+
+    let added = s
+        .add_items(TEST_TABLE_ID, None, new_items.clone().into_iter())
+        .await?;
+    assert_eq!(
+        added.iter().map(|i| &i.name).collect::<Vec<_>>(),
+        new_items.iter().map(|i| &i.name).collect::<Vec<_>>(),
+    );
+
+    let items = s.list_items(TEST_TABLE_ID).await?;
+    assert_eq!(
+        items.iter().map(|i| &i.item_id).collect::<Vec<_>>(),
+        added.iter().map(|i| &i.item_id).collect::<Vec<_>>(),
+    );
+
+    Ok(())
+}
+
+async fn add_empty<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    assert!(s
+        .add_items(TEST_TABLE_ID, None, [].into_iter())
+        .await?
+        .is_empty());
+    assert!(s.list_items(TEST_TABLE_ID).await?.is_empty());
+
+    Ok(())
+}
+
 async fn list_twice<S>(s: S) -> Result<(), S::Error>
 where
     S: Storage,
@@ -162,8 +236,12 @@ where
     let item = test_new_item();
     let item2 = test_new_item_2();
 
-    s.add_items(TEST_TABLE_ID, [item.clone(), item2.clone()].into_iter())
-        .await?;
+    s.add_items(
+        TEST_TABLE_ID,
+        None,
+        [item.clone(), item2.clone()].into_iter(),
+    )
+    .await?;
 
     let items = s.list_items(TEST_TABLE_ID).await?;
     let second_items = s.list_items(TEST_TABLE_ID).await?;
@@ -181,8 +259,12 @@ where
     let item = test_new_item();
     let item2 = test_new_item_2();
 
-    s.add_items(TEST_TABLE_ID, [item.clone(), item2.clone()].into_iter())
-        .await?;
+    s.add_items(
+        TEST_TABLE_ID,
+        None,
+        [item.clone(), item2.clone()].into_iter(),
+    )
+    .await?;
 
     let items = s.list_items(TEST_TABLE_ID).await?;
 
@@ -201,12 +283,15 @@ where
 
     let item = test_new_item();
 
-    s.add_items(TEST_TABLE_ID, [item.clone()].into_iter())
+    s.add_items(TEST_TABLE_ID, None, [item.clone()].into_iter())
         .await?;
     let items = s.list_items(TEST_TABLE_ID).await?;
     let item_id = items[0].item_id.clone();
-    s.remove_items(TEST_TABLE_ID, [item_id.clone()].into_iter())
+    let result = s
+        .remove_items(TEST_TABLE_ID, [item_id.clone()].into_iter())
         .await?;
+    assert_eq!(result.removed, vec![item_id.clone()]);
+    assert!(result.not_found.is_empty());
 
     assert!(s.list_items(TEST_TABLE_ID).await?.is_empty());
     assert_eq!(s.get_item(TEST_TABLE_ID, item_id).await?, None);
@@ -223,16 +308,22 @@ where
     let item = test_new_item();
     let item2 = test_new_item_2();
 
-    s.add_items(TEST_TABLE_ID, [item.clone(), item2.clone()].into_iter())
-        .await?;
-    let items = s.list_items(TEST_TABLE_ID).await?;
-    let item_id = items[0].item_id.clone();
-    let item2_id = items[1].item_id.clone();
-    s.remove_items(
+    s.add_items(
         TEST_TABLE_ID,
-        [item_id.clone(), item2_id.clone()].into_iter(),
+        None,
+        [item.clone(), item2.clone()].into_iter(),
     )
     .await?;
+    let items = s.list_items(TEST_TABLE_ID).await?;
+    let item_id = items[0].item_id.clone();
+    let item2_id = items[1].item_id.clone();
+    let result = s
+        .remove_items(
+            TEST_TABLE_ID,
+            [item2_id.clone(), item_id.clone()].into_iter(),
+        )
+        .await?;
+    assert_eq!(result.removed, vec![item2_id.clone(), item_id.clone()]);
 
     assert!(s.list_items(TEST_TABLE_ID).await?.is_empty());
     assert_eq!(s.get_item(TEST_TABLE_ID, item_id).await?, None);
@@ -249,8 +340,11 @@ where
 
     let item_id: ItemId = 0.into();
 
-    s.remove_items(TEST_TABLE_ID, [item_id.clone()].into_iter())
+    let result = s
+        .remove_items(TEST_TABLE_ID, [item_id.clone()].into_iter())
         .await?;
+    assert!(result.removed.is_empty());
+    assert_eq!(result.not_found, vec![item_id.clone()]);
 
     assert!(s.list_items(TEST_TABLE_ID).await?.is_empty());
     assert_eq!(s.get_item(TEST_TABLE_ID, item_id).await?, None);
@@ -266,7 +360,7 @@ where
 
     let item = test_new_item();
 
-    s.add_items(TEST_TABLE_ID, [item.clone()].into_iter())
+    s.add_items(TEST_TABLE_ID, None, [item.clone()].into_iter())
         .await?;
     let items = s.list_items(TEST_TABLE_ID).await?;
     let item_id = items[0].item_id.clone();
@@ -275,14 +369,768 @@ where
     } else {
         0.into()
     };
+    let result = s
+        .remove_items(
+            TEST_TABLE_ID,
+            [item_id.clone(), missing_item_id.clone(), item_id.clone()].into_iter(),
+        )
+        .await?;
+    assert_eq!(
+        result,
+        RemovedItems {
+            removed: vec![item_id.clone()],
+            not_found: vec![missing_item_id],
+        }
+    );
+
+    // Second removal of same item races with first one and finds nothing
+    let result = s
+        .remove_items(TEST_TABLE_ID, [item_id.clone()].into_iter())
+        .await?;
+    assert_eq!(result.not_found, vec![item_id.clone()]);
+
+    assert!(s.list_items(TEST_TABLE_ID).await?.is_empty());
+    assert_eq!(s.get_item(TEST_TABLE_ID, item_id).await?, None);
+
+    Ok(())
+}
+
+async fn idempotent_replay<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    let key = IdempotencyKey::from("test key".to_string());
+    let item = test_new_item();
+    let item2 = test_new_item_2();
+
+    assert_eq!(
+        s.get_idempotent_items(TEST_TABLE_ID, key.clone()).await?,
+        None
+    );
+    let added = s
+        .add_items(
+            TEST_TABLE_ID,
+            Some(key.clone()),
+            [item.clone(), item2.clone()].into_iter(),
+        )
+        .await?;
+    let replayed = s
+        .add_items(
+            TEST_TABLE_ID,
+            Some(key.clone()),
+            [item.clone(), item2.clone()].into_iter(),
+        )
+        .await?;
+    assert_eq!(replayed, added);
+    assert_eq!(s.list_items(TEST_TABLE_ID).await?.len(), 2);
+    assert_eq!(
+        s.get_idempotent_items(TEST_TABLE_ID, key.clone()).await?,
+        Some(added.clone())
+    );
+
+    // Removed items are not resurrected by replay
+    s.remove_items(TEST_TABLE_ID, [added[0].item_id.clone()].into_iter())
+        .await?;
+    let replayed = s
+        .add_items(TEST_TABLE_ID, Some(key), [item, item2].into_iter())
+        .await?;
+    assert_eq!(replayed, added[1..]);
+    assert_eq!(s.list_items(TEST_TABLE_ID).await?.len(), 1);
+
+    Ok(())
+}
+
+async fn idempotency_key_per_table<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    let key = IdempotencyKey::from("test key".to_string());
+
+    let added = s
+        .add_items(
+            TEST_TABLE_ID,
+            Some(key.clone()),
+            [test_new_item()].into_iter(),
+        )
+        .await?;
+    let other_added = s
+        .add_items(
+            OTHER_TABLE_ID,
+            Some(key.clone()),
+            [test_new_item()].into_iter(),
+        )
+        .await?;
+    assert_ne!(added[0].item_id, other_added[0].item_id);
+    assert_eq!(
+        s.get_idempotent_items(OTHER_TABLE_ID, key).await?,
+        Some(other_added)
+    );
+    assert_eq!(s.list_items(TEST_TABLE_ID).await?.len(), 1);
+    assert_eq!(s.list_items(OTHER_TABLE_ID).await?.len(), 1);
+
+    Ok(())
+}
+
+async fn advance_lifecycle<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    let added = s
+        .add_items(TEST_TABLE_ID, None, [test_new_item()].into_iter())
+        .await?;
+    let item_id = added[0].item_id.clone();
+    assert_eq!(added[0].status, ItemStatus::Ordered);
+
+    let mut expected = added[0].clone();
+    for (offset, to) in [ItemStatus::Cooking, ItemStatus::Ready, ItemStatus::Served]
+        .into_iter()
+        .enumerate()
+    {
+        let at = CREATED_AT + chrono::Duration::minutes(offset as i64 + 1);
+        expected.advance(to, at).unwrap();
+
+        let advanced = s
+            .advance_item(TEST_TABLE_ID, item_id.clone(), to, at)
+            .await?;
+        assert_eq!(advanced, AdvancedItem::Advanced(expected.clone()));
+        assert_eq!(
+            s.get_item(TEST_TABLE_ID, item_id.clone()).await?,
+            Some(expected.clone())
+        );
+    }
+
+    let items = s.list_items(TEST_TABLE_ID).await?;
+    assert_eq!(items[0].status, ItemStatus::Served);
+
+    Ok(())
+}
+
+async fn advance_illegal<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    let added = s
+        .add_items(TEST_TABLE_ID, None, [test_new_item()].into_iter())
+        .await?;
+    let item_id = added[0].item_id.clone();
+
+    // Skipping a state
+    let advanced = s
+        .advance_item(
+            TEST_TABLE_ID,
+            item_id.clone(),
+            ItemStatus::Ready,
+            CREATED_AT,
+        )
+        .await?;
+    assert_eq!(
+        advanced,
+        AdvancedItem::IllegalTransition {
+            from: ItemStatus::Ordered
+        }
+    );
+    // Repeating a state
+    let advanced = s
+        .advance_item(
+            TEST_TABLE_ID,
+            item_id.clone(),
+            ItemStatus::Ordered,
+            CREATED_AT,
+        )
+        .await?;
+    assert_eq!(
+        advanced,
+        AdvancedItem::IllegalTransition {
+            from: ItemStatus::Ordered
+        }
+    );
+    assert_eq!(
+        s.get_item(TEST_TABLE_ID, item_id).await?,
+        Some(added[0].clone())
+    );
+
+    Ok(())
+}
+
+async fn advance_nonexistent<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    let added = s
+        .add_items(TEST_TABLE_ID, None, [test_new_item()].into_iter())
+        .await?;
+
+    let advanced = s
+        .advance_item(
+            OTHER_TABLE_ID,
+            added[0].item_id.clone(),
+            ItemStatus::Cooking,
+            CREATED_AT,
+        )
+        .await?;
+    assert_eq!(advanced, AdvancedItem::NotFound);
+
+    Ok(())
+}
+
+fn test_new_menu_items() -> Vec<NewMenuItem> {
+    ["ramen", "gyoza", "beer"]
+        .into_iter()
+        .map(|name| NewMenuItem {
+            name: name.into(),
+            category: "test category".into(),
+            available: true,
+        })
+        .collect()
+}
+
+async fn menu_add_list<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    assert!(s.list_menu().await?.is_empty());
+
+    let new_items = test_new_menu_items();
+    let added = s.add_menu_items(new_items.clone().into_iter()).await?;
+    assert_eq!(
+        added.iter().map(|i| &i.name).collect::<Vec<_>>(),
+        new_items.iter().map(|i| &i.name).collect::<Vec<_>>()
+    );
+    assert_eq!(s.list_menu().await?, added);
+
+    let more = s.add_menu_items(test_new_menu_items().into_iter()).await?;
+    assert!(more.iter().all(|i| !added.contains(i)));
+    assert_eq!(s.list_menu().await?.len(), added.len() + more.len());
+
+    Ok(())
+}
+
+async fn menu_get_some<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    let added = s.add_menu_items(test_new_menu_items().into_iter()).await?;
+
+    let mut missing_id = added[0].menu_item_id.clone();
+    while added.iter().any(|i| i.menu_item_id == missing_id) {
+        missing_id.0 += 1;
+    }
+    let found = s
+        .get_menu_items(
+            [
+                added[2].menu_item_id.clone(),
+                missing_id,
+                added[0].menu_item_id.clone(),
+            ]
+            .into_iter(),
+        )
+        .await?;
+    assert_eq!(found, vec![added[0].clone(), added[2].clone()]);
+
+    Ok(())
+}
+
+async fn menu_set_available<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    let added = s.add_menu_items(test_new_menu_items().into_iter()).await?;
+    let menu_item_id = added[1].menu_item_id.clone();
+
+    let updated = s
+        .set_menu_item_available(menu_item_id.clone(), false)
+        .await?;
+    assert_eq!(
+        updated,
+        Some(MenuItem {
+            available: false,
+            ..added[1].clone()
+        })
+    );
+    assert_eq!(
+        s.get_menu_items([menu_item_id.clone()].into_iter()).await?,
+        vec![updated.unwrap()]
+    );
+    // Other items are not affected
+    assert!(s
+        .list_menu()
+        .await?
+        .iter()
+        .all(|i| i.available == (i.menu_item_id != menu_item_id)));
+
+    let mut missing_id = menu_item_id;
+    while added.iter().any(|i| i.menu_item_id == missing_id) {
+        missing_id.0 += 1;
+    }
+    assert_eq!(s.set_menu_item_available(missing_id, true).await?, None);
+
+    Ok(())
+}
+
+async fn kitchen_stats<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    assert_eq!(s.kitchen_stats(CREATED_AT).await?, KitchenStats::default());
+
+    let other_menu_item_id = MenuItemId(TEST_MENU_ITEM_ID.0 + 1);
+    let new_items = [
+        test_new_item(),
+        test_new_item(),
+        NewItem {
+            menu_item_id: other_menu_item_id.clone(),
+            ..test_new_item_2()
+        },
+        test_new_item(),
+    ];
+    let added = s
+        .add_items(TEST_TABLE_ID, None, new_items.into_iter())
+        .await?;
+
+    // First two items of test menu item are cooked in 4 and 6 minutes, other one in 10
+    let cook = |item: &ItemInfo, minutes| {
+        let item_id = item.item_id.clone();
+        let s = &s;
+        async move {
+            let cooking_at = CREATED_AT + chrono::Duration::minutes(1);
+            let ready_at = cooking_at + chrono::Duration::minutes(minutes);
+            s.advance_item(
+                TEST_TABLE_ID,
+                item_id.clone(),
+                ItemStatus::Cooking,
+                cooking_at,
+            )
+            .await?;
+            s.advance_item(TEST_TABLE_ID, item_id, ItemStatus::Ready, ready_at)
+                .await
+        }
+    };
+    cook(&added[0], 4).await?;
+    cook(&added[1], 6).await?;
+    cook(&added[2], 10).await?;
+    // Last one is still cooking, so it's queued
+    s.advance_item(
+        TEST_TABLE_ID,
+        added[3].item_id.clone(),
+        ItemStatus::Cooking,
+        CREATED_AT,
+    )
+    .await?;
+
+    assert_eq!(
+        s.kitchen_stats(CREATED_AT).await?,
+        KitchenStats {
+            prep_times: vec![
+                PrepTime {
+                    menu_item_id: TEST_MENU_ITEM_ID,
+                    completed: 2,
+                    average: chrono::Duration::minutes(5),
+                },
+                PrepTime {
+                    menu_item_id: other_menu_item_id,
+                    completed: 1,
+                    average: chrono::Duration::minutes(10),
+                },
+            ],
+            queued: 1,
+        }
+    );
+    // All items were ordered before that
+    assert_eq!(
+        s.kitchen_stats(CREATED_AT + chrono::Duration::seconds(1))
+            .await?,
+        KitchenStats::default()
+    );
+
+    Ok(())
+}
+
+async fn outstanding_forecasts<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    assert_eq!(s.outstanding_items(CREATED_AT).await?, vec![]);
+
+    let mut added = s
+        .add_items(
+            TEST_TABLE_ID,
+            None,
+            [test_new_item(), test_new_item()].into_iter(),
+        )
+        .await?;
+    added.extend(
+        s.add_items(OTHER_TABLE_ID, None, [test_new_item_2()].into_iter())
+            .await?,
+    );
+    added.extend(
+        s.add_items(TEST_TABLE_ID, None, [test_new_item()].into_iter())
+            .await?,
+    );
+
+    // Second item is done, third one is cooking
+    let advance = |idx: usize, to| {
+        let item: &ItemInfo = &added[idx];
+        s.advance_item(item.table_id.clone(), item.item_id.clone(), to, CREATED_AT)
+    };
+    advance(1, ItemStatus::Cooking).await?;
+    advance(1, ItemStatus::Ready).await?;
+    let AdvancedItem::Advanced(cooking) = advance(2, ItemStatus::Cooking).await? else {
+        panic!("Item should be advanced");
+    };
+
+    let outstanding = vec![added[0].clone(), cooking, added[3].clone()];
+    assert_eq!(s.outstanding_items(CREATED_AT).await?, outstanding);
+    // All items were ordered before that
+    assert_eq!(
+        s.outstanding_items(CREATED_AT + chrono::Duration::seconds(1))
+            .await?,
+        vec![]
+    );
+
+    let forecast_ready_at = |idx: usize| FORECAST_READY_AT + chrono::Duration::minutes(idx as i64);
+    let mut events = s.subscribe();
+    let updated = s
+        .update_forecasts(added.iter().enumerate().map(|(idx, item)| ForecastUpdate {
+            table_id: item.table_id.clone(),
+            item_id: item.item_id.clone(),
+            forecast_ready_at: forecast_ready_at(idx),
+        }))
+        .await?;
+    // Item that is ready keeps its forecast
+    assert_eq!(updated, 3);
+
+    // Subscribers get updated items, in any order
+    let mut published = vec![];
+    for _ in 0..updated {
+        match next_event(&mut events).await {
+            ItemEvent::Forecast(item) => published.push(item),
+            other => panic!("expected forecast event, got {other:?}"),
+        }
+    }
+    published.sort_by_key(|item| item.item_id.0);
+    let mut expected = vec![];
+    for idx in [0, 2, 3] {
+        let item = &added[idx];
+        expected.extend(
+            s.get_item(item.table_id.clone(), item.item_id.clone())
+                .await?,
+        );
+    }
+    assert_eq!(published, expected);
+
+    for (idx, item) in added.iter().enumerate() {
+        let item = s
+            .get_item(item.table_id.clone(), item.item_id.clone())
+            .await?
+            .expect("Item should exist");
+        let expected = if idx == 1 {
+            FORECAST_READY_AT
+        } else {
+            forecast_ready_at(idx)
+        };
+        assert_eq!(item.forecast_ready_at, expected);
+    }
+    assert_eq!(
+        s.list_items(TEST_TABLE_ID)
+            .await?
+            .into_iter()
+            .map(|i| i.forecast_ready_at)
+            .collect::<Vec<_>>(),
+        vec![
+            forecast_ready_at(0),
+            FORECAST_READY_AT,
+            forecast_ready_at(3)
+        ]
+    );
+
+    // Empty update does nothing
+    assert_eq!(s.update_forecasts([].into_iter()).await?, 0);
+
+    Ok(())
+}
+
+fn test_new_tables() -> Vec<NewTable> {
+    (1..=3)
+        .map(|idx| NewTable {
+            name: format!("T{idx}"),
+            seats: idx * 2,
+            section: "test section".into(),
+        })
+        .collect()
+}
+
+async fn tables_add_list<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    assert!(s.list_tables().await?.is_empty());
+
+    let new_tables = test_new_tables();
+    let added = s.add_tables(new_tables.clone().into_iter()).await?;
+    assert_eq!(
+        added
+            .iter()
+            .map(|t| (&t.name, t.seats, &t.section, t.active))
+            .collect::<Vec<_>>(),
+        new_tables
+            .iter()
+            .map(|t| (&t.name, t.seats, &t.section, true))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(s.list_tables().await?, added);
+
+    let more = s.add_tables(test_new_tables().into_iter()).await?;
+    assert!(more.iter().all(|t| !added.contains(t)));
+    assert_eq!(s.list_tables().await?.len(), added.len() + more.len());
+
+    Ok(())
+}
+
+async fn tables_get<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    let added = s.add_tables(test_new_tables().into_iter()).await?;
+
+    assert_eq!(
+        s.get_table(added[1].table_id.clone()).await?,
+        Some(added[1].clone())
+    );
+
+    let mut missing_id = added[0].table_id.clone();
+    while added.iter().any(|t| t.table_id == missing_id) {
+        missing_id.0 += 1;
+    }
+    assert_eq!(s.get_table(missing_id).await?, None);
+
+    Ok(())
+}
+
+async fn tables_set_active<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    let added = s.add_tables(test_new_tables().into_iter()).await?;
+    let table_id = added[0].table_id.clone();
+
+    let updated = s.set_table_active(table_id.clone(), false).await?;
+    assert_eq!(
+        updated,
+        Some(Table {
+            active: false,
+            ..added[0].clone()
+        })
+    );
+    assert_eq!(s.get_table(table_id.clone()).await?, updated);
+    // Other tables are not affected
+    assert!(s
+        .list_tables()
+        .await?
+        .iter()
+        .all(|t| t.active == (t.table_id != table_id)));
+
+    let reactivated = s.set_table_active(table_id.clone(), true).await?;
+    assert_eq!(reactivated.as_ref(), Some(&added[0]));
+
+    let mut missing_id = table_id;
+    while added.iter().any(|t| t.table_id == missing_id) {
+        missing_id.0 += 1;
+    }
+    assert_eq!(s.set_table_active(missing_id, false).await?, None);
+
+    Ok(())
+}
+
+/// Storage could publish events asynchronously, so waiting for them a bit
+async fn next_event(events: &mut broadcast::Receiver<ItemEvent>) -> ItemEvent {
+    tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("Event should be published")
+        .expect("Subscriber should not lag behind")
+}
+
+async fn item_events<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    let mut events = s.subscribe();
+
+    let key = IdempotencyKey::from("test key".to_string());
+    let added = s
+        .add_items(
+            TEST_TABLE_ID,
+            Some(key.clone()),
+            [test_new_item(), test_new_item_2()].into_iter(),
+        )
+        .await?;
+    assert_eq!(
+        next_event(&mut events).await,
+        ItemEvent::Added(added[0].clone())
+    );
+    assert_eq!(
+        next_event(&mut events).await,
+        ItemEvent::Added(added[1].clone())
+    );
+
+    // Nothing is changed by replay, illegal transition and removal of removed item
+    s.add_items(TEST_TABLE_ID, Some(key), [test_new_item()].into_iter())
+        .await?;
+
+    let item_id = added[0].item_id.clone();
+    let AdvancedItem::Advanced(advanced) = s
+        .advance_item(
+            TEST_TABLE_ID,
+            item_id.clone(),
+            ItemStatus::Cooking,
+            CREATED_AT,
+        )
+        .await?
+    else {
+        panic!("Item should be advanced");
+    };
+    assert_eq!(next_event(&mut events).await, ItemEvent::Advanced(advanced));
+    s.advance_item(
+        TEST_TABLE_ID,
+        item_id.clone(),
+        ItemStatus::Served,
+        CREATED_AT,
+    )
+    .await?;
+
     s.remove_items(
         TEST_TABLE_ID,
-        [item_id.clone(), missing_item_id.clone()].into_iter(),
+        [item_id.clone(), item_id.clone()].into_iter(),
     )
     .await?;
+    assert_eq!(
+        next_event(&mut events).await,
+        ItemEvent::Removed {
+            table_id: TEST_TABLE_ID,
+            item_id: item_id.clone(),
+        }
+    );
+    s.remove_items(TEST_TABLE_ID, [item_id].into_iter()).await?;
 
-    assert!(s.list_items(TEST_TABLE_ID).await?.is_empty());
-    assert_eq!(s.get_item(TEST_TABLE_ID, item_id).await?, None);
+    let other = s
+        .add_items(OTHER_TABLE_ID, None, [test_new_item()].into_iter())
+        .await?;
+    // Comes right after previous one
+    assert_eq!(
+        next_event(&mut events).await,
+        ItemEvent::Added(other[0].clone())
+    );
+
+    Ok(())
+}
+
+async fn item_changes<S>(s: S) -> Result<(), S::Error>
+where
+    S: Storage,
+{
+    let initial = s.changes_since(TEST_TABLE_ID, TableVersion(0)).await?;
+    assert_eq!(
+        initial,
+        ItemChanges {
+            version: TableVersion(0),
+            reset: true,
+            changed: vec![],
+            removed: vec![],
+        }
+    );
+
+    let added = s
+        .add_items(
+            TEST_TABLE_ID,
+            None,
+            [test_new_item(), test_new_item_2()].into_iter(),
+        )
+        .await?;
+    // Client without anything gets whole table
+    let full = s.changes_since(TEST_TABLE_ID, TableVersion(0)).await?;
+    assert!(full.reset);
+    assert!(full.version > initial.version);
+    assert_eq!(full.changed, s.list_items(TEST_TABLE_ID).await?);
+
+    // Changes of other tables, and requests that change nothing, keep version
+    s.add_items(OTHER_TABLE_ID, None, [test_new_item()].into_iter())
+        .await?;
+    s.add_items(TEST_TABLE_ID, None, [].into_iter()).await?;
+    s.remove_items(TEST_TABLE_ID, [ItemId(-1)].into_iter())
+        .await?;
+    assert_eq!(s.table_version(TEST_TABLE_ID).await?, full.version);
+    let unchanged = s.changes_since(TEST_TABLE_ID, full.version).await?;
+    assert_eq!(
+        unchanged,
+        ItemChanges {
+            version: full.version,
+            reset: false,
+            changed: vec![],
+            removed: vec![],
+        }
+    );
+
+    let AdvancedItem::Advanced(advanced) = s
+        .advance_item(
+            TEST_TABLE_ID,
+            added[0].item_id.clone(),
+            ItemStatus::Cooking,
+            CREATED_AT,
+        )
+        .await?
+    else {
+        panic!("Item should be advanced");
+    };
+    s.remove_items(TEST_TABLE_ID, [added[1].item_id.clone()].into_iter())
+        .await?;
+    let added_later = s
+        .add_items(TEST_TABLE_ID, None, [test_new_item()].into_iter())
+        .await?;
+    let changes = s.changes_since(TEST_TABLE_ID, full.version).await?;
+    assert!(!changes.reset);
+    assert!(changes.version > full.version);
+    assert_eq!(
+        changes.changed,
+        vec![advanced.to_short(), added_later[0].to_short()]
+    );
+    assert_eq!(changes.removed, vec![added[1].item_id.clone()]);
+    assert_eq!(s.table_version(TEST_TABLE_ID).await?, changes.version);
+
+    s.update_forecasts(
+        [ForecastUpdate {
+            table_id: TEST_TABLE_ID,
+            item_id: added_later[0].item_id.clone(),
+            forecast_ready_at: FORECAST_READY_AT + chrono::Duration::minutes(1),
+        }]
+        .into_iter(),
+    )
+    .await?;
+    let forecasts = s.changes_since(TEST_TABLE_ID, changes.version).await?;
+    assert!(forecasts.version > changes.version);
+    assert_eq!(forecasts.changed.len(), 1);
+    assert_eq!(
+        forecasts.changed[0].forecast_ready_at,
+        FORECAST_READY_AT + chrono::Duration::minutes(1)
+    );
+    assert!(forecasts.removed.is_empty());
+    assert_eq!(s.table_version(TEST_TABLE_ID).await?, forecasts.version);
+    assert_eq!(s.table_version(TableId(1000)).await?, TableVersion(0));
+    let item_id = added_later[0].item_id.clone();
+    assert_eq!(
+        s.get_versioned_item(TEST_TABLE_ID, item_id.clone()).await?,
+        (s.get_item(TEST_TABLE_ID, item_id).await?, forecasts.version)
+    );
+    assert_eq!(
+        s.get_versioned_item(TEST_TABLE_ID, added[1].item_id.clone())
+            .await?,
+        (None, forecasts.version)
+    );
+
+    // Cursor storage has never given out
+    let ahead = TableVersion(forecasts.version.0 + 100);
+    let reset = s.changes_since(TEST_TABLE_ID, ahead).await?;
+    assert!(reset.reset);
+    assert_eq!(reset.version, forecasts.version);
+    assert_eq!(reset.changed, s.list_items(TEST_TABLE_ID).await?);
 
     Ok(())
 }